@@ -1,7 +1,8 @@
-#[macro_use]
 extern crate criterion;
 use criterion::*;
 
+use std::time::{Duration, Instant};
+
 extern crate allocators;
 use allocators::{
     // no bookkeeping
@@ -9,13 +10,15 @@ use allocators::{
     // has bookkeeping
     handlemap::HandleMap,   // free list with an explicit stack
     handlemap2::HandleMap2, // free list with implicit stack
+    buddy::Buddy,           // free lists per order, split and merged
+    tlsf::Tlsf,             // segregated free lists, constant time
+    arena::Arena,           // bump pointer, freed all at once
 };
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("alloc (freelist)", |b| {
         let mut fl: FreeList<u64> = FreeList::new();
-        let mut handles = Vec::new();
-        handles.reserve(100000);
+        let mut handles = Vec::with_capacity(100000);
         b.iter(|| {
             for i in 0..100000 {
                 handles.push(fl.insert(i));
@@ -29,8 +32,7 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     c.bench_function("alloc (handlemap)", |b| {
         let mut hm: HandleMap<u64> = HandleMap::new();
-        let mut handles = Vec::new();
-        handles.reserve(100000);
+        let mut handles = Vec::with_capacity(100000);
         b.iter(|| {
             for i in 0..100000 {
                 handles.push(hm.insert(i));
@@ -44,8 +46,7 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     c.bench_function("alloc (handlemap2)", |b| {
         let mut hm2: HandleMap2<u64> = HandleMap2::new();
-        let mut handles = Vec::new();
-        handles.reserve(100000);
+        let mut handles = Vec::with_capacity(100000);
         b.iter(|| {
             for i in 0..100000 {
                 handles.push(hm2.insert(i));
@@ -56,7 +57,99 @@ fn criterion_benchmark(c: &mut Criterion) {
             }
         });
     });
+
+    c.bench_function("alloc (buddy)", |b| {
+        let mut buddy = Buddy::new(17); /* 2^17 minimal blocks */
+        let mut blocks = Vec::with_capacity(100000);
+        b.iter(|| {
+            for _ in 0..100000 {
+                blocks.push(buddy.alloc(0).unwrap());
+            }
+
+            for block in blocks.drain(..) {
+                buddy.free(block);
+            }
+        });
+    });
+
+    c.bench_function("alloc (tlsf)", |b| {
+        let mut tlsf = Tlsf::new(4 << 20);
+        let mut blocks = Vec::with_capacity(100000);
+        b.iter(|| {
+            for _ in 0..100000 {
+                blocks.push(tlsf.alloc(8).unwrap());
+            }
+
+            for block in blocks.drain(..) {
+                tlsf.free(block);
+            }
+        });
+    });
+
+    c.bench_function("alloc (arena)", |b| {
+        let mut arena: Arena<u64> = Arena::new();
+        b.iter(|| {
+            for i in 0..100000 {
+                arena.alloc(i);
+            }
+
+            arena.reset();
+        });
+    });
+}
+
+/* Criterion reports averages; a real-time thread cares about the slowest
+ * single call. Time every call of a mixed workload and print the worst,
+ * next to the 99.99th percentile, which the scheduler disturbs less. */
+fn worst_case_latency(_: &mut Criterion) {
+    fn worst<F: FnMut(usize)>(name: &str, mut f: F) {
+        let mut times: Vec<Duration> = Vec::with_capacity(200000);
+        for i in 0..200000 {
+            let start = Instant::now();
+            f(i);
+            times.push(start.elapsed());
+        }
+        times.sort();
+        println!("worst case latency ({}): {:?} (99.99%: {:?})",
+            name, times[times.len() - 1], times[times.len() * 9999 / 10000]);
+    }
+
+    /* Sizes vary, and every third call frees an earlier allocation */
+    let size = |i: usize| (i * 37) % 512 + 1;
+
+    let mut fl: FreeList<u64> = FreeList::new();
+    let mut handles = Vec::with_capacity(200000);
+    worst("freelist", |i| {
+        if i % 3 == 2 {
+            let h = handles.swap_remove(i % handles.len());
+            fl.remove(h);
+        } else {
+            handles.push(fl.insert(i as u64));
+        }
+    });
+
+    let mut buddy = Buddy::new(22);
+    let mut blocks = Vec::with_capacity(200000);
+    worst("buddy", |i| {
+        if i % 3 == 2 {
+            let block = blocks.swap_remove(i % blocks.len());
+            buddy.free(block);
+        } else {
+            blocks.push(buddy.alloc(Buddy::order_for(size(i))).unwrap());
+        }
+    });
+
+    let mut tlsf = Tlsf::new(64 << 20);
+    let mut blocks = Vec::with_capacity(200000);
+    worst("tlsf", |i| {
+        if i % 3 == 2 {
+            let block = blocks.swap_remove(i % blocks.len());
+            tlsf.free(block);
+        } else {
+            blocks.push(tlsf.alloc(size(i)).unwrap());
+        }
+    });
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, criterion_benchmark, worst_case_latency);
 criterion_main!(benches);
@@ -0,0 +1,152 @@
+//! `#[derive(Trace)]` for objects living in an `allocators::pile::Pile`.
+//!
+//! Every field is traced with its own `Trace` implementation, which covers
+//! `Pointer`, `WeakPointer`, `Option<Pointer>`, `Vec<Pointer>` and nested
+//! `Trace` types.
+//! Fields that can not hold pointers, and do not implement `Trace`, are
+//! opted out with `#[trace(skip)]`.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Index};
+
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::allocators::pile::Trace));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (trace, trace_mut, trace_weak_mut) = match input.data {
+        Data::Struct(ref s) => {
+            let (bind, fields) = bindings(&s.fields, quote!(ref))?;
+            let (bind_mut, _) = bindings(&s.fields, quote!(ref mut))?;
+            let trace = quote! {
+                let #name #bind = *self;
+                #( ::allocators::pile::Trace::trace(#fields, tracer); )*
+            };
+            let trace_mut = quote! {
+                let #name #bind_mut = *self;
+                #( ::allocators::pile::Trace::trace_mut(#fields, tracer); )*
+            };
+            let trace_weak_mut = quote! {
+                let #name #bind_mut = *self;
+                #( ::allocators::pile::Trace::trace_weak_mut(#fields, tracer); )*
+            };
+            (trace, trace_mut, trace_weak_mut)
+        },
+        Data::Enum(ref e) => {
+            let mut arms = Vec::new();
+            let mut arms_mut = Vec::new();
+            let mut arms_weak_mut = Vec::new();
+            for variant in &e.variants {
+                let v = &variant.ident;
+                let (bind, fields) = bindings(&variant.fields, quote!(ref))?;
+                let (bind_mut, _) = bindings(&variant.fields, quote!(ref mut))?;
+                arms.push(quote! {
+                    #name::#v #bind => {
+                        #( ::allocators::pile::Trace::trace(#fields, tracer); )*
+                    }
+                });
+                arms_mut.push(quote! {
+                    #name::#v #bind_mut => {
+                        #( ::allocators::pile::Trace::trace_mut(#fields, tracer); )*
+                    }
+                });
+                arms_weak_mut.push(quote! {
+                    #name::#v #bind_mut => {
+                        #( ::allocators::pile::Trace::trace_weak_mut(#fields, tracer); )*
+                    }
+                });
+            }
+            (quote! { match *self { #(#arms)* } },
+             quote! { match *self { #(#arms_mut)* } },
+             quote! { match *self { #(#arms_weak_mut)* } })
+        },
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(name, "Trace can not be derived for unions"));
+        },
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::allocators::pile::Trace for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn trace(&self, tracer: &mut dyn FnMut(::allocators::pile::Pointer)) {
+                #trace
+            }
+
+            #[allow(unused_variables)]
+            fn trace_mut(&mut self,
+                tracer: &mut dyn FnMut(&mut ::allocators::pile::Pointer)) {
+                #trace_mut
+            }
+
+            #[allow(unused_variables)]
+            fn trace_weak_mut(&mut self,
+                tracer: &mut dyn FnMut(&mut ::allocators::pile::WeakPointer)) {
+                #trace_weak_mut
+            }
+        }
+    })
+}
+
+/* A pattern binding every traced field with `mode` (`ref` or `ref mut`),
+ * and the names it binds */
+fn bindings(fields: &Fields, mode: TokenStream)
+    -> syn::Result<(TokenStream, Vec<TokenStream>)>
+{
+    let mut patterns = Vec::new();
+    let mut names = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let skip = skipped(&field.attrs)?;
+        let binding = format_ident!("__field{}", i);
+        let member = match field.ident {
+            Some(ref ident) => quote!(#ident),
+            None            => { let i = Index::from(i); quote!(#i) },
+        };
+
+        if skip {
+            patterns.push(quote!(#member: _));
+        } else {
+            patterns.push(quote!(#member: #mode #binding));
+            names.push(quote!(#binding));
+        }
+    }
+
+    let pattern = match *fields {
+        Fields::Unit => quote!(),
+        _            => quote!({ #(#patterns,)* .. }),
+    };
+    Ok((pattern, names))
+}
+
+/* Whether a field carries `#[trace(skip)]` */
+fn skipped(attrs: &[syn::Attribute]) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in attrs {
+        if !attr.path().is_ident("trace") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip`"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
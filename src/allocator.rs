@@ -0,0 +1,292 @@
+//! The standard `Allocator` trait, for the allocators of this crate.
+//!
+//! The trait is unstable, so this module is only built with the `nightly`
+//! feature. It lets standard collections live in memory managed here:
+//!
+//! ```ignore
+//! let slab = SlabAllocator::new(Layout::new::<[u64; 8]>());
+//! let mut v: Vec<u64, _> = Vec::with_capacity_in(8, &slab);
+//! ```
+//!
+//! `Allocator` works through `&self`, so the slab and the bump arena are
+//! wrapped in a `RefCell`; `SizeClassAlloc` already locks. Blocks are
+//! resized in place where the allocator allows it, and moved otherwise.
+
+use std::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use std::cell::RefCell;
+use std::ptr::{self, NonNull};
+
+use arena::{self, ByteArena};
+use sizeclass::SizeClassAlloc;
+use slab::Slab;
+use stats::Stats;
+
+/// A `Slab` as an `Allocator`. It serves every layout that fits its
+/// blocks, and fails for larger ones.
+#[derive(Debug)]
+pub struct SlabAllocator {
+    slab: RefCell<Slab>,
+}
+
+impl SlabAllocator {
+    pub fn new(block: Layout) -> SlabAllocator {
+        SlabAllocator { slab: RefCell::new(Slab::new(block)) }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.slab.borrow().stats()
+    }
+
+    fn fits(&self, layout: Layout) -> Option<usize> {
+        let block = self.slab.borrow().block_layout();
+        if layout.size() <= block.size() && layout.align() <= block.align() {
+            Some(block.size())
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl Allocator for SlabAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let size = self.fits(layout).ok_or(AllocError)?;
+        let p = self.slab.borrow_mut().alloc();
+        Ok(NonNull::slice_from_raw_parts(p, size))
+    }
+
+    unsafe fn deallocate(&self, p: NonNull<u8>, _: Layout) {
+        self.slab.borrow_mut().free(p);
+    }
+
+    /* Every block has the full block size; there is nothing larger */
+    unsafe fn grow(&self, p: NonNull<u8>, _: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        let size = self.fits(new).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(p, size))
+    }
+
+    unsafe fn shrink(&self, p: NonNull<u8>, _: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        let size = self.fits(new).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(p, size))
+    }
+}
+
+/// A `ByteArena` of fixed capacity as an `Allocator`.
+///
+/// The arena does not grow, as that would move the blocks handed out, so
+/// allocating fails once it is full. Freeing the last block gives its room
+/// back; everything else is only freed by `reset`.
+#[derive(Debug)]
+pub struct BumpAllocator {
+    arena: RefCell<ByteArena>,
+}
+
+impl BumpAllocator {
+    /// An allocator with room for at least `n` bytes.
+    pub fn new(n: usize) -> BumpAllocator {
+        BumpAllocator { arena: RefCell::new(ByteArena::with_capacity(n)) }
+    }
+
+    /// Free every block. This takes `&mut self`, so no collection can still
+    /// be using one.
+    pub fn reset(&mut self) {
+        self.arena.get_mut().reset();
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.arena.borrow().stats()
+    }
+}
+
+unsafe impl Allocator for BumpAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.align() > arena::CHUNK {
+            return Err(AllocError);
+        }
+        let mut arena = self.arena.borrow_mut();
+        let span = arena.try_alloc(layout).ok_or(AllocError)?;
+        let p = unsafe { NonNull::new_unchecked(arena.as_mut_ptr(span)) };
+        Ok(NonNull::slice_from_raw_parts(p, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, p: NonNull<u8>, layout: Layout) {
+        let mut arena = self.arena.borrow_mut();
+        let span = arena.span(p.as_ptr(), layout.size());
+        let _ = arena.try_resize(span, 0);
+    }
+
+    unsafe fn grow(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        self.resize(p, old, new)
+    }
+
+    unsafe fn shrink(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        self.resize(p, old, new)
+    }
+}
+
+impl BumpAllocator {
+    /* The last block grows and shrinks in place, any block shrinks in
+     * place, as long as it is aligned for the new layout */
+    unsafe fn resize(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        if (p.as_ptr() as usize).is_multiple_of(new.align()) {
+            let mut arena = self.arena.borrow_mut();
+            let span = arena.span(p.as_ptr(), old.size());
+            if arena.try_resize(span, new.size()).is_some() || new.size() <= old.size() {
+                return Ok(NonNull::slice_from_raw_parts(p, new.size()));
+            }
+        }
+        move_block(self, p, old, new)
+    }
+}
+
+unsafe impl Allocator for SizeClassAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        /* `GlobalAlloc` does not take empty layouts */
+        if layout.size() == 0 {
+            let p = unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) };
+            return Ok(NonNull::slice_from_raw_parts(p, 0));
+        }
+        let p = unsafe { GlobalAlloc::alloc(self, layout) };
+        NonNull::new(p)
+            .map(|p| NonNull::slice_from_raw_parts(p, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, p: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            GlobalAlloc::dealloc(self, p.as_ptr(), layout);
+        }
+    }
+
+    unsafe fn grow(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        self.resize(p, old, new)
+    }
+
+    unsafe fn shrink(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        self.resize(p, old, new)
+    }
+}
+
+impl SizeClassAlloc {
+    /* `realloc` keeps a block that stays in its size class */
+    unsafe fn resize(&self, p: NonNull<u8>, old: Layout, new: Layout)
+        -> Result<NonNull<[u8]>, AllocError>
+    {
+        if old.size() != 0 && new.size() != 0 && old.align() == new.align() {
+            let q = GlobalAlloc::realloc(self, p.as_ptr(), old, new.size());
+            return NonNull::new(q)
+                .map(|q| NonNull::slice_from_raw_parts(q, new.size()))
+                .ok_or(AllocError);
+        }
+        move_block(self, p, old, new)
+    }
+}
+
+/* Resize by allocating a new block, copying, and freeing the old one */
+unsafe fn move_block<A: Allocator>(a: &A, p: NonNull<u8>, old: Layout, new: Layout)
+    -> Result<NonNull<[u8]>, AllocError>
+{
+    let q = a.allocate(new)?;
+    ptr::copy_nonoverlapping(p.as_ptr(), q.as_ptr() as *mut u8, old.size().min(new.size()));
+    a.deallocate(p, old);
+    Ok(q)
+}
+
+/* Collections in every allocator, checked for their contents and for
+ * giving their memory back */
+#[cfg(all(test, feature = "nightly"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slab() {
+        let slab = SlabAllocator::new(Layout::new::<[u64; 16]>());
+
+        let boxes: Vec<Box<u64, &SlabAllocator>> = (0..100).map(|i| Box::new_in(i, &slab)).collect();
+        assert_eq!(boxes.iter().map(|b| **b).sum::<u64>(), 4950);
+        assert_eq!(slab.stats().live, 100);
+        drop(boxes);
+        assert_eq!(slab.stats().live, 0);
+
+        /* Grows in place up to the block size, and no further */
+        let mut v: Vec<u64, &SlabAllocator> = Vec::with_capacity_in(2, &slab);
+        v.extend(0..16);
+        assert_eq!(v.iter().sum::<u64>(), 120);
+        assert_eq!(slab.stats().live, 1);
+        assert!(v.try_reserve(1).is_err());
+        v.shrink_to_fit();
+        assert_eq!(v.len(), 16);
+    }
+
+    #[test]
+    fn bump() {
+        let mut bump = BumpAllocator::new(64 * 1024);
+        {
+            let b = Box::new_in([7u8; 100], &bump);
+            let mut v: Vec<u32, &BumpAllocator> = Vec::new_in(&bump);
+            for i in 0..1000 {
+                v.push(i);
+            }
+            assert_eq!(v.iter().sum::<u32>(), 499_500);
+            assert!(b.iter().all(|&x| x == 7));
+
+            /* The vector is the last block, so it grew in place */
+            assert!(bump.stats().live < 100 + 4 * 1024 + 64);
+
+            let mut big: Vec<u8, &BumpAllocator> = Vec::new_in(&bump);
+            assert!(big.try_reserve(1 << 20).is_err());
+        }
+        bump.reset();
+        assert_eq!(bump.stats().live, 0);
+    }
+
+    #[test]
+    fn size_class() {
+        let alloc = SizeClassAlloc::new();
+        {
+            let b = Box::new_in(String::from("boxed"), &alloc);
+            let mut v: Vec<u64, &SizeClassAlloc> = Vec::new_in(&alloc);
+            for i in 0..10_000 {
+                v.push(i);
+            }
+            v.truncate(10);
+            v.shrink_to_fit();
+            assert_eq!(v.iter().sum::<u64>(), 45);
+            assert_eq!(*b, "boxed");
+            assert_eq!(alloc.live(), 2);
+
+            let empty: Vec<u64, &SizeClassAlloc> = Vec::with_capacity_in(0, &alloc);
+            let zst = Box::new_in((), &alloc);
+            assert_eq!(alloc.live(), 2);
+            drop((empty, zst));
+        }
+        assert_eq!(alloc.live(), 0);
+    }
+
+    #[test]
+    fn size_class_pages() {
+        /* Every block of a page but the first is handed out before the
+         * next page is taken */
+        let alloc = SizeClassAlloc::new();
+        let boxes: Vec<Box<[u8; 2048], &SizeClassAlloc>> =
+            (0..31).map(|_| Box::new_in([0; 2048], &alloc)).collect();
+        assert_eq!(alloc.pages(), 1);
+        let more = Box::new_in([0u8; 2048], &alloc);
+        assert_eq!(alloc.pages(), 2);
+        drop((boxes, more));
+        assert_eq!(alloc.live(), 0);
+    }
+}
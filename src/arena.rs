@@ -0,0 +1,341 @@
+//! Bump allocation for objects that die together.
+//!
+//! An arena hands out room at the end of its storage and never frees single
+//! objects. Instead, `checkpoint` remembers how far it is filled, and
+//! `reset_to` frees everything allocated since in one go; `reset` frees
+//! everything. This suits e.g. per-frame allocations, which would otherwise
+//! be removed from a `FreeList` one by one.
+//!
+//! Indices and spans of freed objects are handed out again by later
+//! allocations, so they must not be used after a reset that freed them.
+
+use std::alloc::Layout;
+use std::fmt;
+use std::slice;
+
+use stats::{Counters, Stats};
+
+/// How far an arena was filled when `checkpoint` was called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mark {
+    top: usize,
+}
+
+/// An arena of values of one type.
+#[derive(Debug)]
+pub struct Arena<T> {
+    memory:     Vec<T>,
+    counters:   Counters,
+}
+
+const DEFAULT_CAPACITY: usize = 16;
+
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena::new()
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Arena<T> {
+        Arena::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(n: usize) -> Arena<T> {
+        Arena {
+            memory:     Vec::with_capacity(n),
+            counters:   Counters::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, t: T) -> usize {
+        if self.memory.len() == self.memory.capacity() {
+            self.counters.grow();
+        }
+        self.memory.push(t);
+        self.counters.insert(self.memory.len());
+        self.memory.len() - 1
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        self.memory.get(i)
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        self.memory.get_mut(i)
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    pub fn checkpoint(&self) -> Mark {
+        Mark { top: self.memory.len() }
+    }
+
+    /// Free everything allocated since `mark`. Takes constant time unless
+    /// `T` needs to be dropped. Panics if an earlier reset already freed
+    /// past `mark`.
+    pub fn reset_to(&mut self, mark: Mark) {
+        assert!(mark.top <= self.memory.len(), "{:?} was reset past already", mark);
+        self.memory.truncate(mark.top);
+    }
+
+    pub fn reset(&mut self) {
+        self.reset_to(Mark { top: 0 });
+    }
+
+    pub fn stats(&self) -> Stats {
+        let capacity = self.memory.capacity();
+        let free = capacity - self.memory.len();
+        let mut stats = Stats {
+            live:               self.memory.len(),
+            capacity,
+            free,
+            largest_free_run:   free,   /* all of it is at the end */
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+}
+
+/// Where `ByteArena::alloc` put a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    offset: usize,
+    len:    usize,
+}
+
+/* The buffer grows in chunks, which are aligned to their size */
+pub(crate) const CHUNK: usize = 4096;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct Chunk([u8; CHUNK]);
+
+/// An arena of raw byte blocks of any layout.
+///
+/// Blocks are carved out of one buffer, which may move when it grows, so
+/// they are reached through their `Span`.
+pub struct ByteArena {
+    chunks:     Vec<Chunk>,
+    top:        usize,
+    counters:   Counters,
+}
+
+impl Default for ByteArena {
+    fn default() -> ByteArena {
+        ByteArena::new()
+    }
+}
+
+impl ByteArena {
+    pub fn new() -> ByteArena {
+        ByteArena::with_capacity(0)
+    }
+
+    /// An arena with room for at least `n` bytes.
+    pub fn with_capacity(n: usize) -> ByteArena {
+        let mut arena = ByteArena {
+            chunks:     Vec::new(),
+            top:        0,
+            counters:   Counters::new(),
+        };
+        if n > 0 {
+            arena.grow(n);
+        }
+        arena
+    }
+
+    /// Allocate a block. Fresh memory is zeroed, but memory freed by a reset
+    /// keeps whatever was written to it. Panics if the alignment is larger
+    /// than 4096.
+    pub fn alloc(&mut self, layout: Layout) -> Span {
+        match self.try_alloc(layout) {
+            Some(span) => span,
+            None       => self.grow_and_alloc(layout),
+        }
+    }
+
+    /// Allocate a block without growing. Returns `None` if it does not fit
+    /// in the room left.
+    pub fn try_alloc(&mut self, layout: Layout) -> Option<Span> {
+        assert!(layout.align() <= CHUNK, "alignment {} is larger than a chunk", layout.align());
+        let offset = self.top.div_ceil(layout.align()) * layout.align();
+        let end = offset + layout.size();
+        if end > self.capacity() {
+            return None;
+        }
+
+        self.top = end;
+        self.counters.insert(self.top);
+        Some(Span { offset, len: layout.size() })
+    }
+
+    fn grow_and_alloc(&mut self, layout: Layout) -> Span {
+        let end = self.top.div_ceil(layout.align()) * layout.align() + layout.size();
+        self.grow(end);
+        self.try_alloc(layout).expect("allocating will always succeed after growing")
+    }
+
+    /// The bytes of a block. Returns `None` if a reset freed it.
+    pub fn bytes(&self, span: Span) -> Option<&[u8]> {
+        if span.offset + span.len > self.top {
+            return None;
+        }
+        Some(&self.buffer()[span.offset..span.offset + span.len])
+    }
+
+    pub fn bytes_mut(&mut self, span: Span) -> Option<&mut [u8]> {
+        if span.offset + span.len > self.top {
+            return None;
+        }
+        Some(&mut self.buffer_mut()[span.offset..span.offset + span.len])
+    }
+
+    pub fn checkpoint(&self) -> Mark {
+        Mark { top: self.top }
+    }
+
+    /// Free every block allocated since `mark`, in constant time. Panics if
+    /// an earlier reset already freed past `mark`.
+    pub fn reset_to(&mut self, mark: Mark) {
+        assert!(mark.top <= self.top, "{:?} was reset past already", mark);
+        self.top = mark.top;
+    }
+
+    pub fn reset(&mut self) {
+        self.reset_to(Mark { top: 0 });
+    }
+
+    /// Memory statistics, counted in bytes.
+    pub fn stats(&self) -> Stats {
+        let capacity = self.capacity();
+        let mut stats = Stats {
+            live:               self.top,
+            capacity,
+            free:               capacity - self.top,
+            largest_free_run:   capacity - self.top,
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.len() * CHUNK
+    }
+
+    /* Where a span starts in memory, until the buffer grows */
+    #[cfg(feature = "nightly")]
+    pub(crate) fn as_mut_ptr(&mut self, span: Span) -> *mut u8 {
+        unsafe { (self.chunks.as_mut_ptr() as *mut u8).add(span.offset) }
+    }
+
+    /* The span at `p`, which this arena handed out */
+    #[cfg(feature = "nightly")]
+    pub(crate) fn span(&self, p: *const u8, len: usize) -> Span {
+        let offset = p as usize - self.chunks.as_ptr() as usize;
+        Span { offset, len }
+    }
+
+    /* Resize a block in place, which works for the last one only */
+    #[cfg(feature = "nightly")]
+    pub(crate) fn try_resize(&mut self, span: Span, len: usize) -> Option<Span> {
+        let end = span.offset + len;
+        if span.offset + span.len != self.top || end > self.capacity() {
+            return None;
+        }
+        self.top = end;
+        Some(Span { offset: span.offset, len })
+    }
+
+    /* Grow to fit `end` bytes, at least doubling */
+    fn grow(&mut self, end: usize) {
+        let n = end.div_ceil(CHUNK).max(2 * self.chunks.len());
+        self.chunks.resize(n, Chunk([0; CHUNK]));
+        self.counters.grow();
+    }
+
+    fn buffer(&self) -> &[u8] {
+        /* The chunks are plain bytes, laid out back to back */
+        unsafe { slice::from_raw_parts(self.chunks.as_ptr() as *const u8, self.capacity()) }
+    }
+
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        let len = self.capacity();
+        unsafe { slice::from_raw_parts_mut(self.chunks.as_mut_ptr() as *mut u8, len) }
+    }
+}
+
+impl fmt::Debug for ByteArena {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ByteArena")
+            .field("capacity", &self.capacity())
+            .field("top", &self.top)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_to_checkpoint() {
+        let mut arena = Arena::new();
+        let a = arena.alloc(1);
+        let mark = arena.checkpoint();
+        let b = arena.alloc(2);
+        assert_eq!(arena.get(b), Some(&2));
+
+        arena.reset_to(mark);
+        assert_eq!(arena.get(a), Some(&1));
+        assert_eq!(arena.get(b), None);
+        assert_eq!(arena.alloc(3), b);
+        arena.reset();
+        assert!(arena.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "reset past already")]
+    fn reset_past_mark() {
+        let mut arena = Arena::new();
+        arena.alloc(1);
+        let mark = arena.checkpoint();
+        arena.reset();
+        arena.reset_to(mark);
+    }
+
+    #[test]
+    fn byte_alignment() {
+        let mut arena = ByteArena::new();
+        arena.alloc(Layout::from_size_align(3, 1).unwrap());
+        for &align in &[8, 64, 4096] {
+            let span = arena.alloc(Layout::from_size_align(5, align).unwrap());
+            assert_eq!(span.offset % align, 0);
+            assert_eq!(arena.bytes(span).unwrap().len(), 5);
+        }
+    }
+
+    #[test]
+    fn bytes_freed_by_reset() {
+        let mut arena = ByteArena::with_capacity(16);
+        let mark = arena.checkpoint();
+        let span = arena.alloc(Layout::new::<u64>());
+        arena.bytes_mut(span).unwrap().copy_from_slice(&[7; 8]);
+
+        /* Growing moves the buffer, but not the contents */
+        arena.alloc(Layout::from_size_align(3 * CHUNK, 1).unwrap());
+        assert_eq!(arena.bytes(span), Some(&[7; 8][..]));
+
+        arena.reset_to(mark);
+        assert_eq!(arena.bytes(span), None);
+        assert_eq!(arena.stats().live, 0);
+    }
+}
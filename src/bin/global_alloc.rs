@@ -0,0 +1,70 @@
+extern crate allocators;
+
+use std::collections::HashMap;
+use std::thread;
+
+use allocators::freelist::FreeList;
+use allocators::sizeclass::SizeClassAlloc;
+
+#[global_allocator]
+static ALLOC: SizeClassAlloc = SizeClassAlloc::new();
+
+/* Churn through allocations of many sizes, and check nothing leaks */
+fn workload() {
+    let mut words: HashMap<String, usize> = HashMap::new();
+    for i in 0..20_000 {
+        let word = format!("word{}", i % 1_500);
+        *words.entry(word).or_insert(0) += 1;
+    }
+    assert_eq!(words.len(), 1_500);
+    assert_eq!(words["word7"], 14);
+
+    let mut boxes: Vec<Box<[u8]>> = Vec::new();
+    for i in 0..5_000 {
+        boxes.push(vec![i as u8; i % 3_000].into_boxed_slice());
+        if i % 3 == 0 {
+            boxes.swap_remove(i % boxes.len());
+        }
+    }
+    for b in &boxes {
+        assert!(b.iter().all(|&x| x == b[0]));
+    }
+
+    let mut fl = FreeList::new();
+    let keys: Vec<usize> = (0..10_000).map(|i| fl.insert(i.to_string())).collect();
+    for &k in keys.iter().step_by(2) {
+        fl.remove(k);
+    }
+    assert_eq!(fl.get(keys[1]).map(|s| s.as_str()), Some("1"));
+
+    let mut grown = Vec::new();
+    for i in 0..100_000u32 {
+        grown.push(i);
+    }
+    assert_eq!(grown.iter().map(|&x| u64::from(x)).sum::<u64>(), 4_999_950_000);
+
+    /* The classes are shared between threads */
+    let threads: Vec<_> = (0..4).map(|t| thread::spawn(move || {
+        let mut v: Vec<String> = Vec::new();
+        for i in 0..20_000 {
+            v.push(format!("{}-{}", t, i));
+            if i % 2 == 0 {
+                v.pop();
+            }
+        }
+        v.len()
+    })).collect();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), 10_000);
+    }
+}
+
+fn main() {
+    let before = ALLOC.live();
+    workload();
+    let after = ALLOC.live();
+
+    println!("size-class allocator: {} pages, {} small blocks live ({} before the workload)",
+        ALLOC.pages(), after, before);
+    assert_eq!(before, after, "the workload leaked small blocks");
+}
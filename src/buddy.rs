@@ -0,0 +1,386 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use invariant::{self, Violation};
+use stats::{Counters, Stats};
+
+/// A binary buddy allocator over one contiguous region.
+///
+/// The region is `2^max_order` minimal blocks of 16 bytes. A block of order
+/// `k` is `2^k` minimal blocks, and aligned to its size within the region.
+/// Allocating splits larger blocks in halves (buddies) until one of the
+/// right order is free; freeing merges a block with its buddy for as long
+/// as the buddy is free too.
+///
+/// Every order has its own free list, threaded through the free blocks
+/// themselves like in `FreeList`. The lists are doubly linked, so a buddy
+/// can be taken out of the middle of its list when merging.
+pub struct Buddy {
+    memory:     Vec<u8>,
+    heads:      Vec<Option<usize>>,     /* per order, a minimal block index */
+    tags:       Vec<Option<usize>>,     /* order of the free block starting here */
+    taken:      Vec<Option<usize>>,     /* order of the handed out block starting here */
+    live:       usize,                  /* minimal blocks handed out */
+    counters:   Counters,
+}
+
+/// A block handed out by a `Buddy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    index: usize, /* in minimal blocks */
+    order: usize,
+}
+
+/// Size of a block of order 0, which fits the two free-list links.
+pub const MIN_BLOCK: usize = 16;
+
+const LINK: usize = 8;
+const NIL: u64 = u64::MAX;
+
+impl Buddy {
+    /// A region of `MIN_BLOCK << max_order` bytes, all free. Panics if that
+    /// does not fit in a `usize`.
+    pub fn new(max_order: usize) -> Buddy {
+        let blocks = u32::try_from(max_order).ok().and_then(|k| 1usize.checked_shl(k));
+        let bytes = blocks.and_then(|n| n.checked_mul(MIN_BLOCK))
+            .unwrap_or_else(|| panic!("max order {} is too large", max_order));
+        let blocks = bytes / MIN_BLOCK;
+        let mut buddy = Buddy {
+            memory:     vec![0; bytes],
+            heads:      vec![None; max_order + 1],
+            tags:       vec![None; blocks],
+            taken:      vec![None; blocks],
+            live:       0,
+            counters:   Counters::new(),
+        };
+        buddy.push(0, max_order);
+        buddy
+    }
+
+    /// The smallest order whose blocks fit `size` bytes.
+    pub fn order_for(size: usize) -> usize {
+        let blocks = size.div_ceil(MIN_BLOCK).max(1);
+        blocks.next_power_of_two().trailing_zeros() as usize
+    }
+
+    pub fn max_order(&self) -> usize {
+        self.heads.len() - 1
+    }
+
+    /// Allocate a block of `2^order` minimal blocks. Returns `None` if no
+    /// free block is large enough.
+    pub fn alloc(&mut self, order: usize) -> Option<Block> {
+        /* The smallest free block that fits, split down to size */
+        let mut k = (order..self.heads.len()).find(|&k| self.heads[k].is_some())?;
+        let index = self.pop(k);
+        while k > order {
+            k -= 1;
+            self.push(index + (1 << k), k);
+        }
+
+        self.taken[index] = Some(order);
+        self.live += 1 << order;
+        self.counters.insert(self.live);
+        debug_validate!(self);
+        Some(Block { index, order })
+    }
+
+    /// Give a block back, merging it with its buddy while it is free.
+    /// Panics if the block is not handed out, e.g. because it was already
+    /// freed, or if it does not fit in this allocator's region.
+    pub fn free(&mut self, block: Block) {
+        let fits = block.order <= self.max_order()
+            && block.index.is_multiple_of(1 << block.order)
+            && block.index + (1 << block.order) <= self.tags.len();
+        if !fits {
+            panic!("block not from this allocator: {:?}", block);
+        }
+
+        match self.taken.get_mut(block.index) {
+            Some(taken) if *taken == Some(block.order) => *taken = None,
+            _                                          => panic!("double free of {:?}", block),
+        }
+
+        let mut index = block.index;
+        let mut order = block.order;
+        self.live -= 1 << order;
+
+        while order < self.max_order() {
+            let buddy = index ^ (1 << order);
+            if self.tags[buddy] != Some(order) {
+                break;
+            }
+            self.unlink(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+
+        self.counters.remove();
+        debug_validate!(self);
+    }
+
+    pub fn bytes(&self, block: Block) -> &[u8] {
+        let start = block.index * MIN_BLOCK;
+        &self.memory[start..start + (MIN_BLOCK << block.order)]
+    }
+
+    pub fn bytes_mut(&mut self, block: Block) -> &mut [u8] {
+        let start = block.index * MIN_BLOCK;
+        &mut self.memory[start..start + (MIN_BLOCK << block.order)]
+    }
+
+    /// Number of free blocks of every order, from 0 to `max_order`.
+    pub fn free_blocks(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.heads.len()];
+        for order in self.tags.iter().flatten() {
+            counts[*order] += 1;
+        }
+        counts
+    }
+
+    /// Memory statistics, counted in minimal blocks. The largest free run
+    /// is the largest free block: neighbouring free blocks that are not
+    /// buddies can not be allocated as one.
+    pub fn stats(&self) -> Stats {
+        let capacity = self.tags.len();
+        let largest = self.tags.iter().flatten().map(|&k| 1 << k).max().unwrap_or(0);
+        let mut stats = Stats {
+            live:               self.live,
+            capacity,
+            free:               capacity - self.live,
+            largest_free_run:   largest,
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check every free list, that no two free buddies are left unmerged,
+    /// and that free and handed out blocks tile the whole region.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let len = self.tags.len();
+        let mut free = 0;
+        for (order, &head) in self.heads.iter().enumerate() {
+            let on_list = invariant::walk("buddy", head, len, |i| {
+                match self.tags[i] {
+                    Some(k) if k == order => Some(self.next(i)),
+                    _                     => None,
+                }
+            })?;
+            free += on_list.iter().filter(|&&f| f).count() << order;
+        }
+
+        for (i, tag) in self.tags.iter().enumerate() {
+            if let Some(order) = *tag {
+                let buddy = i ^ (1 << order);
+                if order < self.max_order() && self.tags[buddy] == Some(order) {
+                    return Err(Violation::Uncoalesced { list: "buddy", offset: i * MIN_BLOCK });
+                }
+            }
+        }
+
+        self.validate_tiling()?;
+        invariant::count("buddy", self.live, free, len)
+    }
+
+    /* Blocks, free or not, follow each other without gaps, each aligned to
+     * its size and with no other block starting inside it */
+    fn validate_tiling(&self) -> Result<(), Violation> {
+        let len = self.tags.len();
+        let mut live = 0;
+        let mut i = 0;
+        while i < len {
+            let order = match (self.tags[i], self.taken[i]) {
+                (Some(k), None)    => k,
+                (None, Some(k))    => {
+                    live += 1 << k;
+                    k
+                },
+                (Some(_), Some(_)) => {
+                    return Err(Violation::Overlap { list: "buddy", offset: i * MIN_BLOCK });
+                },
+                /* Not part of any block: lost */
+                (None, None)       => {
+                    return Err(Violation::Count { list: "buddy", live, free: i - live, capacity: len });
+                },
+            };
+            let end = i + (1 << order);
+            if !i.is_multiple_of(1 << order) || end > len {
+                return Err(Violation::OutOfBounds { list: "buddy", index: i });
+            }
+            if let Some(j) = (i + 1..end).find(|&j| self.tags[j].is_some() || self.taken[j].is_some()) {
+                return Err(Violation::Overlap { list: "buddy", offset: j * MIN_BLOCK });
+            }
+            i = end;
+        }
+        invariant::count("buddy", self.live, len - live, len)
+    }
+
+    /* The free lists: links live in the first bytes of a free block */
+
+    fn push(&mut self, index: usize, order: usize) {
+        let head = self.heads[order];
+        self.set_next(index, head);
+        self.set_prev(index, None);
+        if let Some(h) = head {
+            self.set_prev(h, Some(index));
+        }
+        self.heads[order] = Some(index);
+        self.tags[index] = Some(order);
+    }
+
+    fn pop(&mut self, order: usize) -> usize {
+        let index = self.heads[order].expect("corrupt free list");
+        self.unlink(index, order);
+        index
+    }
+
+    fn unlink(&mut self, index: usize, order: usize) {
+        let (prev, next) = (self.prev(index), self.next(index));
+        match prev {
+            Some(p) => self.set_next(p, next),
+            None    => self.heads[order] = next,
+        }
+        if let Some(n) = next {
+            self.set_prev(n, prev);
+        }
+        self.tags[index] = None;
+    }
+
+    fn next(&self, index: usize) -> Option<usize> {
+        self.link(index * MIN_BLOCK)
+    }
+
+    fn prev(&self, index: usize) -> Option<usize> {
+        self.link(index * MIN_BLOCK + LINK)
+    }
+
+    fn set_next(&mut self, index: usize, next: Option<usize>) {
+        self.set_link(index * MIN_BLOCK, next)
+    }
+
+    fn set_prev(&mut self, index: usize, prev: Option<usize>) {
+        self.set_link(index * MIN_BLOCK + LINK, prev)
+    }
+
+    fn link(&self, at: usize) -> Option<usize> {
+        let mut bytes = [0; LINK];
+        bytes.copy_from_slice(&self.memory[at..at + LINK]);
+        match u64::from_le_bytes(bytes) {
+            NIL => None,
+            i   => Some(i as usize),
+        }
+    }
+
+    fn set_link(&mut self, at: usize, link: Option<usize>) {
+        let link = link.map_or(NIL, |i| i as u64);
+        self.memory[at..at + LINK].copy_from_slice(&link.to_le_bytes());
+    }
+}
+
+impl fmt::Debug for Buddy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Buddy")
+            .field("max_order", &self.max_order())
+            .field("live", &self.live)
+            .field("free_blocks", &self.free_blocks())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_merge() {
+        let mut buddy = Buddy::new(4);
+        let a = buddy.alloc(0).unwrap();
+        let b = buddy.alloc(2).unwrap();
+        let c = buddy.alloc(1).unwrap();
+        assert_eq!(buddy.stats().live, 1 + 4 + 2);
+        assert_eq!(buddy.validate(), Ok(()));
+
+        buddy.free(b);
+        buddy.free(a);
+        buddy.free(c);
+        assert_eq!(buddy.free_blocks(), vec![0, 0, 0, 0, 1]);
+        assert_eq!(buddy.validate(), Ok(()));
+    }
+
+    #[test]
+    fn full() {
+        let mut buddy = Buddy::new(2);
+        let blocks: Vec<_> = (0..4).map(|_| buddy.alloc(0).unwrap()).collect();
+        assert_eq!(buddy.alloc(0), None);
+        for b in blocks {
+            buddy.free(b);
+        }
+        assert!(buddy.alloc(2).is_some());
+    }
+
+    #[test]
+    fn order_for() {
+        assert_eq!(Buddy::order_for(0), 0);
+        assert_eq!(Buddy::order_for(MIN_BLOCK), 0);
+        assert_eq!(Buddy::order_for(MIN_BLOCK + 1), 1);
+        assert_eq!(Buddy::order_for(5 * MIN_BLOCK), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let mut buddy = Buddy::new(3);
+        let b = buddy.alloc(1).unwrap();
+        buddy.free(b);
+        buddy.free(b);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_after_merge() {
+        /* y merges into x's block, which starts at x */
+        let mut buddy = Buddy::new(3);
+        let x = buddy.alloc(0).unwrap();
+        let y = buddy.alloc(0).unwrap();
+        buddy.free(y);
+        buddy.free(x);
+        buddy.free(y);
+    }
+
+    #[test]
+    fn validate_finds_nested_free_block() {
+        let mut buddy = Buddy::new(3);
+        let x = buddy.alloc(0).unwrap();
+        let y = buddy.alloc(0).unwrap();
+        buddy.free(y);
+        buddy.free(x);
+        /* Sneak block 1 onto the free list, inside the free block 0 */
+        buddy.push(1, 0);
+        assert_eq!(buddy.validate(), Err(Violation::Overlap { list: "buddy", offset: MIN_BLOCK }));
+    }
+
+    #[test]
+    #[should_panic(expected = "block not from this allocator")]
+    fn foreign_block() {
+        let mut big = Buddy::new(6);
+        let block = big.alloc(5).unwrap();
+        let mut small = Buddy::new(4);
+        small.free(block);
+    }
+
+    #[test]
+    #[should_panic(expected = "block not from this allocator")]
+    fn misaligned_block() {
+        let mut b = Buddy::new(4);
+        b.alloc(0).unwrap();
+        b.free(Block { index: 1, order: 1 });
+    }
+
+    #[test]
+    #[should_panic(expected = "max order 60 is too large")]
+    fn max_order_overflows() {
+        Buddy::new(60);
+    }
+}
@@ -0,0 +1,118 @@
+//! Heap dumps in the Graphviz DOT language.
+//!
+//! Every live object becomes a node, labelled with where it lives and a
+//! caller-provided description, and every pointer between objects becomes
+//! an edge. Render with e.g. `dot -Tsvg heap.dot > heap.svg`.
+
+use std::io::{self, Write};
+
+/// What a dump highlights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Roots are drawn bold.
+    Roots,
+    /// Roots are drawn bold, and objects not reachable from any root are
+    /// filled red, so leaks stand out.
+    Unreachable,
+}
+
+/* A graph being written; nodes are named by a prefix and a number */
+pub(crate) struct Graph<'a, W: Write + 'a> {
+    w:      &'a mut W,
+    prefix: &'static str,
+}
+
+impl<'a, W: Write> Graph<'a, W> {
+    pub fn begin(w: &'a mut W, name: &str, prefix: &'static str) -> io::Result<Graph<'a, W>> {
+        writeln!(w, "digraph \"{}\" {{", escape(name))?;
+        writeln!(w, "    node [shape=box, fontname=monospace];")?;
+        Ok(Graph { w, prefix })
+    }
+
+    pub fn node(&mut self, id: usize, label: &str, root: bool, unreachable: bool) -> io::Result<()> {
+        let style = match (root, unreachable) {
+            (true, _)      => ", style=bold, penwidth=2",
+            (false, true)  => ", style=filled, fillcolor=\"#f4a6a6\"",
+            (false, false) => "",
+        };
+        writeln!(self.w, "    {}{} [label=\"{}\"{}];", self.prefix, id, escape(label), style)
+    }
+
+    /* A node for a pointer to an object that is gone */
+    pub fn dangling(&mut self, id: usize, label: &str) -> io::Result<()> {
+        writeln!(self.w, "    {}{} [label=\"{}\", style=dashed, color=red];",
+            self.prefix, id, escape(label))
+    }
+
+    pub fn edge(&mut self, from: usize, to: usize) -> io::Result<()> {
+        writeln!(self.w, "    {0}{1} -> {0}{2};", self.prefix, from, to)
+    }
+
+    pub fn end(self) -> io::Result<()> {
+        writeln!(self.w, "}}")
+    }
+}
+
+/// Which of `len` nodes can be reached from `roots`, following `edges`.
+pub(crate) fn reachable(len: usize, roots: &[usize], edges: &[Vec<usize>]) -> Vec<bool> {
+    let mut seen = vec![false; len];
+    let mut work = roots.to_vec();
+    while let Some(n) = work.pop() {
+        if n >= len || seen[n] {
+            continue;
+        }
+        seen[n] = true;
+        work.extend(&edges[n]);
+    }
+    seen
+}
+
+/* The contents of a quoted DOT string */
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c    => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reachable_follows_edges() {
+        let edges = vec![vec![1], vec![2], vec![1], vec![0], vec![9]];
+        assert_eq!(reachable(5, &[0], &edges), [true, true, true, false, false]);
+        assert_eq!(reachable(5, &[4], &edges), [false, false, false, false, true]);
+        assert_eq!(reachable(5, &[], &edges), [false; 5]);
+    }
+
+    #[test]
+    fn styles_and_escapes() {
+        let mut out = Vec::new();
+        {
+            let mut graph = Graph::begin(&mut out, "a \"b\"", "n").unwrap();
+            graph.node(0, "root\\", true, true).unwrap();
+            graph.node(1, "two\nlines", false, true).unwrap();
+            graph.node(2, "plain", false, false).unwrap();
+            graph.dangling(3, "gone").unwrap();
+            graph.edge(0, 3).unwrap();
+            graph.end().unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "digraph \"a \\\"b\\\"\" {");
+        assert_eq!(lines[2], "    n0 [label=\"root\\\\\", style=bold, penwidth=2];");
+        assert_eq!(lines[3], "    n1 [label=\"two\\nlines\", style=filled, fillcolor=\"#f4a6a6\"];");
+        assert_eq!(lines[4], "    n2 [label=\"plain\"];");
+        assert_eq!(lines[5], "    n3 [label=\"gone\", style=dashed, color=red];");
+        assert_eq!(lines[6], "    n0 -> n3;");
+        assert_eq!(lines[7], "}");
+    }
+}
@@ -1,11 +1,16 @@
 use std::mem;
 use std::fmt;
+use std::ops::{Index, IndexMut};
+
+use invariant::{self, Violation};
+use stats::{self, Counters, Stats};
 
 #[derive(Debug)]
 pub struct FreeList<T> {
     memory: Vec<Entry<T>>,
     head:   Option<usize>,
     len:    usize,
+    counters: Counters,
 }
 
 #[derive(Debug)]
@@ -16,6 +21,12 @@ enum Entry<T> {
 
 const DEFAULT_CAPACITY: usize = 16;
 
+impl<T> Default for FreeList<T> {
+    fn default() -> FreeList<T> {
+        FreeList::new()
+    }
+}
+
 impl<T> FreeList<T> {
     pub fn new() -> FreeList<T> {
         FreeList::with_capacity(DEFAULT_CAPACITY)
@@ -26,16 +37,20 @@ impl<T> FreeList<T> {
             memory: Vec::new(),
             head:   None,
             len:    0,
+            counters: Counters::new(),
         };
         fl.grow(n);
         fl
     }
 
     pub fn insert(&mut self, t: T) -> usize {
-        match self.try_insert(t) {
+        let i = match self.try_insert(t) {
             Ok(i)  => i,
             Err(t) => self.grow_and_insert(t)
-        }
+        };
+        self.counters.insert(self.len);
+        debug_validate!(self);
+        i
     }
 
     pub fn get(&self, i: usize) -> Option<&T> {
@@ -45,6 +60,32 @@ impl<T> FreeList<T> {
         }
     }
 
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        match self.memory.get_mut(i) {
+            Some(Entry::Taken { ref mut value }) => Some(value),
+            _                                    => None
+        }
+    }
+
+    /// Mutable references to several values at once. Returns `None` if any
+    /// index is free, or if an index is given twice.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, indices: [usize; N])
+        -> Option<[&mut T; N]>
+    {
+        for (n, &i) in indices.iter().enumerate() {
+            if self.get(i).is_none() || indices[..n].contains(&i) {
+                return None;
+            }
+        }
+
+        /* The indices are taken and distinct, so the references never alias */
+        let memory = self.memory.as_mut_ptr();
+        Some(indices.map(|i| match unsafe { &mut *memory.add(i) } {
+            Entry::Taken { ref mut value } => value,
+            Entry::Free  { .. }            => unreachable!(),
+        }))
+    }
+
     pub fn remove(&mut self, i: usize) -> Option<T> {
         /* We need ownership of the old entry, hence mem::replace */
         let entry = mem::replace(
@@ -56,6 +97,8 @@ impl<T> FreeList<T> {
             Entry::Taken { value: t } => {
                 self.head = Some(i);
                 self.len -= 1;
+                self.counters.remove();
+                debug_validate!(self);
                 Some(t)
             },
             e @ Entry::Free { .. } => {
@@ -67,6 +110,9 @@ impl<T> FreeList<T> {
     }
 
     pub fn grow(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
         let old_len = self.memory.len();
         let new_len = old_len + n;
         let old_head = self.head;
@@ -81,6 +127,41 @@ impl<T> FreeList<T> {
         }
 
         self.head = Some(old_len);
+        self.counters.grow();
+        debug_validate!(self);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let free = self.memory.iter().map(|e| matches!(e, Entry::Free { .. }));
+        let mut stats = Stats {
+            live:               self.len,
+            capacity:           self.memory.len(),
+            free:               self.memory.len() - self.len,
+            largest_free_run:   stats::largest_run(free),
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check the free list: it must stay in bounds, never loop, only link
+    /// free entries, and together with `len` account for every entry.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let free = invariant::walk("data", self.head, self.memory.len(), |i| {
+            match self.memory[i] {
+                Entry::Free { next } => Some(next),
+                Entry::Taken { .. }  => None,
+            }
+        })?;
+
+        let free = free.iter().filter(|&&f| f).count();
+        let live = self.memory.iter()
+            .filter(|e| matches!(e, Entry::Taken { .. }))
+            .count();
+
+        /* Free entries that are not on the list are leaked */
+        invariant::count("data", self.len, free, self.memory.len())?;
+        invariant::count("data", live, free, self.memory.len())
     }
 
     fn try_insert(&mut self, t: T) -> Result<usize, T> {
@@ -100,7 +181,7 @@ impl<T> FreeList<T> {
 
     fn grow_and_insert(&mut self, t: T) -> usize {
         /* Double the length */
-        let len = self.memory.len();
+        let len = self.memory.len().max(1);
         self.grow(len);
 
         /* Allocate t */
@@ -110,6 +191,36 @@ impl<T> FreeList<T> {
     }
 }
 
+/* Why indexing failed; i is known to not hold a value */
+fn missing(i: usize, len: usize) -> ! {
+    if i >= len {
+        panic!("index {} was never allocated (capacity is {})", i, len)
+    } else {
+        panic!("index {} is free", i)
+    }
+}
+
+impl<T> Index<usize> for FreeList<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        match self.memory.get(i) {
+            Some(Entry::Taken { ref value }) => value,
+            _                                => missing(i, self.memory.len()),
+        }
+    }
+}
+
+impl<T> IndexMut<usize> for FreeList<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        let len = self.memory.len();
+        match self.memory.get_mut(i) {
+            Some(Entry::Taken { ref mut value }) => value,
+            _                                    => missing(i, len),
+        }
+    }
+}
+
 impl<T: fmt::Debug> fmt::Display for FreeList<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "FreeList. Next insert: {:?}. Len: {}.", self.head, self.len)?;
@@ -122,3 +233,42 @@ impl<T: fmt::Debug> fmt::Display for FreeList<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_removed_entries() {
+        let mut fl = FreeList::with_capacity(2);
+        let a = fl.insert('a');
+        let b = fl.insert('b');
+        let c = fl.insert('c');
+        assert_eq!(fl.remove(b), Some('b'));
+        assert_eq!(fl.remove(b), None);
+        assert_eq!(fl.insert('d'), b);
+        assert_eq!((fl[a], fl[b], fl[c]), ('a', 'd', 'c'));
+        assert_eq!(fl.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_finds_corruption() {
+        let mut fl: FreeList<u32> = FreeList::with_capacity(4);
+        fl.memory[3] = Entry::Free { next: Some(0) };
+        assert!(matches!(fl.validate(), Err(Violation::Cycle { .. })));
+
+        let mut fl: FreeList<u32> = FreeList::with_capacity(4);
+        fl.memory[3] = Entry::Free { next: Some(10) };
+        assert!(matches!(fl.validate(), Err(Violation::OutOfBounds { .. })));
+
+        let mut fl = FreeList::with_capacity(4);
+        fl.insert(1);
+        fl.memory[3] = Entry::Free { next: Some(0) };
+        assert!(matches!(fl.validate(), Err(Violation::CrossLink { .. })));
+
+        /* Entry 0 drops off the list */
+        let mut fl: FreeList<u32> = FreeList::with_capacity(4);
+        fl.head = Some(1);
+        assert!(matches!(fl.validate(), Err(Violation::Count { .. })));
+    }
+}
@@ -1,9 +1,15 @@
+use std::ops::{Index, IndexMut};
+
+use invariant::{self, Violation};
+use stats::{self, Counters, Stats};
+
 #[derive(Debug)]
 pub struct HandleMap<T> {
     data:       Vec<T>,
     slots:      Vec<Slot>,
     free_data:  Vec<usize>,
-    free_slots: Vec<usize>
+    free_slots: Vec<usize>,
+    counters:   Counters,
 }
 
 #[derive(Debug)]
@@ -18,6 +24,12 @@ pub struct Handle {
     slot:       usize, /* address in the handles vector */
 }
 
+impl<T> Default for HandleMap<T> {
+    fn default() -> HandleMap<T> {
+        HandleMap::new()
+    }
+}
+
 impl<T> HandleMap<T> {
     pub fn new() -> HandleMap<T> {
         HandleMap {
@@ -25,6 +37,7 @@ impl<T> HandleMap<T> {
             slots:      Vec::new(),
             free_data:  Vec::new(),
             free_slots: Vec::new(),
+            counters:   Counters::new(),
         }
     }
 
@@ -38,13 +51,16 @@ impl<T> HandleMap<T> {
             },
             /* No reusable address */
             None => {
+                if self.data.len() == self.data.capacity() {
+                    self.counters.grow();
+                }
                 self.data.push(t);
                 self.data.len() - 1
             }
         };
 
         /* Get a new handle to the adress */
-        if let Some(i) = self.free_slots.pop() {
+        let handle = if let Some(i) = self.free_slots.pop() {
             /* Re-use a slot */
             let current_gen = self.slots[i].generation + 1;
             self.slots[i] = Slot {
@@ -52,10 +68,10 @@ impl<T> HandleMap<T> {
                 address:    addr,
             };
 
-            return Handle {
+            Handle {
                 generation: current_gen,
                 slot:       i,
-            };
+            }
         } else {
             /* No reusable slots */
             self.slots.push(Slot {
@@ -63,18 +79,22 @@ impl<T> HandleMap<T> {
                 address:    addr,
             });
 
-            return Handle { 
+            Handle {
                 generation: 1,
                 slot:       self.slots.len() - 1,
-            };
+            }
         };
+
+        self.counters.insert(self.len());
+        debug_validate!(self);
+        handle
     }
 
     pub fn remove(&mut self, h: Handle) {
         let generation = self.slots[h.slot].generation;
         
         if h.generation != generation {
-            return ();
+            return;
         }
 
         let address = self.slots[h.slot].address;
@@ -88,6 +108,8 @@ impl<T> HandleMap<T> {
         /* schedule handle for reuse */
         self.free_slots.push(h.slot);
 
+        self.counters.remove();
+        debug_validate!(self);
     }
 
     pub fn get(&self, h: Handle) -> Option<&T> {
@@ -113,4 +135,135 @@ impl<T> HandleMap<T> {
 
         Some(&mut self.data[address])
     }
+
+    /// Mutable references to several values at once. Returns `None` if any
+    /// handle is stale, or if two handles refer to the same value.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, handles: [Handle; N])
+        -> Option<[&mut T; N]>
+    {
+        let mut addresses = [0; N];
+        for (i, &h) in handles.iter().enumerate() {
+            let slot = self.slots.get(h.slot)?;
+            if slot.generation != h.generation || addresses[..i].contains(&slot.address) {
+                return None;
+            }
+            addresses[i] = slot.address;
+        }
+
+        /* The addresses are valid and distinct, so the references never alias */
+        let data = self.data.as_mut_ptr();
+        Some(addresses.map(|a| unsafe { &mut *data.add(a) }))
+    }
+
+    /* The address behind a valid handle; panics with the reason otherwise */
+    fn expect_address(&self, h: Handle) -> usize {
+        match self.slots.get(h.slot) {
+            None => panic!("handle to slot {}, which was never allocated", h.slot),
+            Some(slot) if slot.generation != h.generation => panic!(
+                "stale handle to slot {}: handle is generation {}, slot is generation {}",
+                h.slot, h.generation, slot.generation),
+            Some(slot) => slot.address,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut free = vec![false; self.data.len()];
+        for &a in &self.free_data {
+            free[a] = true;
+        }
+
+        let mut stats = Stats {
+            live:               self.len(),
+            capacity:           self.data.len(),
+            free:               self.free_data.len(),
+            largest_free_run:   stats::largest_run(free),
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /* Number of slots in use */
+    fn len(&self) -> usize {
+        self.slots.len() - self.free_slots.len()
+    }
+
+    /// Check the free stacks, and that every live slot refers to its own,
+    /// unfreed data.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let free_slots = invariant::walk_stack("slot", &self.free_slots, self.slots.len())?;
+        let free_data = invariant::walk_stack("data", &self.free_data, self.data.len())?;
+
+        let mut seen = vec![false; self.data.len()];
+        let mut live = 0;
+        for (i, slot) in self.slots.iter().enumerate() {
+            if free_slots[i] {
+                continue;
+            }
+            live += 1;
+
+            let address = slot.address;
+            if address >= self.data.len() || free_data[address] {
+                return Err(Violation::Dangling { slot: i, address });
+            }
+            if seen[address] {
+                return Err(Violation::Shared { slot: i, address });
+            }
+            seen[address] = true;
+        }
+
+        invariant::count("data", live, self.free_data.len(), self.data.len())
+    }
+}
+
+impl<T> Index<Handle> for HandleMap<T> {
+    type Output = T;
+
+    fn index(&self, h: Handle) -> &T {
+        &self.data[self.expect_address(h)]
+    }
+}
+
+impl<T> IndexMut<Handle> for HandleMap<T> {
+    fn index_mut(&mut self, h: Handle) -> &mut T {
+        let address = self.expect_address(h);
+        &mut self.data[address]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handles() {
+        let mut m = HandleMap::new();
+        let a = m.insert(1);
+        m.remove(a);
+        let b = m.insert(2);
+        assert_eq!(m.get(a), None);
+        assert_eq!(m[b], 2);
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_finds_corruption() {
+        let mut m = HandleMap::new();
+        m.insert(1);
+        m.insert(2);
+        m.slots[1].address = 0;
+        assert_eq!(m.validate(), Err(Violation::Shared { slot: 1, address: 0 }));
+
+        let mut m = HandleMap::new();
+        m.insert(1);
+        m.slots[0].address = 5;
+        assert_eq!(m.validate(), Err(Violation::Dangling { slot: 0, address: 5 }));
+
+        let mut m = HandleMap::new();
+        let a = m.insert(1);
+        m.insert(2);
+        m.remove(a);
+        m.free_slots.push(0);
+        assert!(m.validate().is_err());
+    }
 }
@@ -1,32 +1,61 @@
 use std::mem;
 use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::ops::{Index, IndexMut};
+
+use dot::{self, Graph, Mode};
+use invariant::{self, Violation};
+use stats::{self, Counters, Stats};
+
+mod history;
+mod transaction;
+
+pub use self::history::History;
+pub use self::transaction::Transaction;
 
 #[derive(Debug)]
 pub struct HandleMap2<T> {
     data:           Vec<Entry<T>>,
     slots:          Vec<Slot>,
     free_data_head: Option<usize>,
-    free_slot_head: Option<usize>
+    free_slot_head: Option<usize>,
+    live:           usize,
+    counters:       Counters,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Entry<T> {
     Free  { next: Option<usize> },
     Taken { value: T },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Slot {
     generation: usize, /* used to invalidate refrences */
+    latest:     usize, /* highest generation handed out, which undo does not lower */
     address:    Entry<usize>, /* index in the data vector */
 }
 
+/* What an insert took, so undoing it can put things back as they were */
+#[derive(Debug, Clone, Copy)]
+struct Prior {
+    generation: usize, /* of the slot, before it was handed out */
+    appended:   bool,  /* whether the data was pushed, rather than reused */
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Handle {
     generation: usize, 
     slot:       usize, /* index in the slots vector */
 }
 
+impl<T> Default for HandleMap2<T> {
+    fn default() -> HandleMap2<T> {
+        HandleMap2::new()
+    }
+}
+
 impl<T> HandleMap2<T> {
     pub fn new() -> HandleMap2<T> {
         HandleMap2 {
@@ -34,11 +63,75 @@ impl<T> HandleMap2<T> {
             slots:          Vec::new(),
             free_data_head: None,
             free_slot_head: None,
+            live:           0,
+            counters:       Counters::new(),
         }
     }
 
     pub fn insert(&mut self, t: T) -> Handle {
+        self.insert_undoable(t).0
+    }
+
+    /* Insert, and return what `uninsert` needs to undo it */
+    fn insert_undoable(&mut self, t: T) -> (Handle, Prior) {
+        let prior = Prior {
+            generation: self.free_slot_head.map_or(0, |n| self.slots[n].generation),
+            appended:   self.free_data_head.is_none(),
+        };
+
         /* Get an address, create a slot, return a handle */
+        let addr = self.store(t);
+        let handle = self.get_handle(addr);
+
+        self.live += 1;
+        self.counters.insert(self.live);
+        debug_validate!(self);
+        (handle, prior)
+    }
+
+    /* Put a removed value back behind its old handle, whose slot must be
+     * free and not handed out again since */
+    fn insert_at(&mut self, h: Handle, t: T) -> Prior {
+        match self.slots.get(h.slot) {
+            Some(&Slot { latest, address: Entry::Free { .. }, .. }) if h.generation <= latest => (),
+            _ => panic!("handle to slot {} can not be restored", h.slot),
+        }
+        let prior = Prior {
+            generation: self.slots[h.slot].generation,
+            appended:   self.free_data_head.is_none(),
+        };
+        self.unlink_slot(h.slot);
+        let addr = self.store(t);
+        self.slots[h.slot].generation = h.generation;
+        self.slots[h.slot].address = Entry::Taken { value: addr };
+
+        self.live += 1;
+        self.counters.insert(self.live);
+        debug_validate!(self);
+        prior
+    }
+
+    /* Undo the insert that handed out `h`, after everything since was
+     * undone: the value's memory and slot go back to the heads of their
+     * free lists, where the insert took them from, or memory it appended
+     * is dropped. The slot keeps its latest generation, so `h` stays stale */
+    fn uninsert(&mut self, h: Handle, prior: Prior) -> T {
+        let addr = self.expect_address(h);
+        let value = self.remove(h).expect("address is taken");
+        self.slots[h.slot].generation = prior.generation;
+
+        if prior.appended && addr + 1 == self.data.len() {
+            self.free_data_head = match self.data.pop() {
+                Some(Entry::Free { next }) => next,
+                _                          => panic!("corrupt free (data) list"),
+            };
+        }
+        debug_validate!(self);
+        value
+    }
+
+    /* Put a value in reusable memory, or at the end; its address */
+    fn store(&mut self, t: T) -> usize {
         if let Some(addr) = self.free_data_head {
             /* data[addr] is reusable memory */
             match self.data[addr] {
@@ -46,15 +139,42 @@ impl<T> HandleMap2<T> {
                 Entry::Free  { next } => {
                     self.free_data_head = next;
                     self.data[addr] = Entry::Taken { value: t };
-                    self.get_handle(addr)
+                    addr
                 }
             }
         } else {
             /* No reusable memory */
+            if self.data.len() == self.data.capacity() {
+                self.counters.grow();
+            }
             self.data.push(Entry::Taken { value: t });
-            let addr = self.data.len() - 1;
-            self.get_handle(addr)
+            self.data.len() - 1
+        }
+    }
+
+    /* Take a free slot out of the middle of its list */
+    fn unlink_slot(&mut self, slot: usize) {
+        let next = match self.slots[slot].address {
+            Entry::Free  { next } => next,
+            Entry::Taken { .. }   => panic!("corrupt free (slot) list"),
+        };
+        if self.free_slot_head == Some(slot) {
+            self.free_slot_head = next;
+            return;
+        }
+
+        let mut i = self.free_slot_head;
+        while let Some(j) = i {
+            match self.slots[j].address {
+                Entry::Free { next: Some(n) } if n == slot => {
+                    self.slots[j].address = Entry::Free { next };
+                    return;
+                },
+                Entry::Free  { next: n } => i = n,
+                Entry::Taken { .. }      => break,
+            }
         }
+        panic!("corrupt free (slot) list")
     }
 
     pub fn remove(&mut self, h: Handle) -> Option<T> {
@@ -83,6 +203,9 @@ impl<T> HandleMap2<T> {
             },
         );
         self.free_data_head = Some(addr);
+        self.live -= 1;
+        self.counters.remove();
+        debug_validate!(self);
 
         /* return the old value */
         match old {
@@ -95,8 +218,8 @@ impl<T> HandleMap2<T> {
         /* Create/Reuse a slot, and get a handle to it */
         if let Some(n) = self.free_slot_head {
             /* Slot #n is reusable */
-            let gen = self.slots[n].generation;
-            
+            let gen = self.slots[n].latest;
+
             self.free_slot_head = match self.slots[n].address {
                 Entry::Taken { .. }   => panic!("corrupt free (slot) list"),
                 Entry::Free  { next } => next,
@@ -104,6 +227,7 @@ impl<T> HandleMap2<T> {
 
             self.slots[n] = Slot {
                 generation: gen + 1,
+                latest:     gen + 1,
                 address:    Entry::Taken { value: addr }
             };
 
@@ -115,6 +239,7 @@ impl<T> HandleMap2<T> {
             /* No reusable slots */
             self.slots.push(Slot {
                 generation: 1,
+                latest:     1,
                 address:    Entry::Taken { value: addr }
             });
 
@@ -162,11 +287,203 @@ impl<T> HandleMap2<T> {
         }
     }
 
+    pub fn stats(&self) -> Stats {
+        let free = self.data.iter().map(|e| matches!(e, Entry::Free { .. }));
+        let mut stats = Stats {
+            live:               self.live,
+            capacity:           self.data.len(),
+            free:               self.data.len() - self.live,
+            largest_free_run:   stats::largest_run(free),
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check both free lists, and that every taken slot refers to its own,
+    /// taken data entry.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let free_data = invariant::walk("data", self.free_data_head, self.data.len(), |i| {
+            match self.data[i] {
+                Entry::Free { next } => Some(next),
+                Entry::Taken { .. }  => None,
+            }
+        })?;
+
+        let free_slots = invariant::walk("slot", self.free_slot_head, self.slots.len(), |i| {
+            match self.slots[i].address {
+                Entry::Free { next } => Some(next),
+                Entry::Taken { .. }  => None,
+            }
+        })?;
+
+        let mut seen = vec![false; self.data.len()];
+        let mut live = 0;
+        for (i, slot) in self.slots.iter().enumerate() {
+            let address = match slot.address {
+                Entry::Free  { .. }    => continue,
+                Entry::Taken { value } => value,
+            };
+            live += 1;
+
+            match self.data.get(address) {
+                Some(Entry::Taken { .. }) => (),
+                _ => return Err(Violation::Dangling { slot: i, address }),
+            }
+            if seen[address] {
+                return Err(Violation::Shared { slot: i, address });
+            }
+            seen[address] = true;
+        }
+
+        let free_data = free_data.iter().filter(|&&f| f).count();
+        let free_slots = free_slots.iter().filter(|&&f| f).count();
+        invariant::count("data", self.live, free_data, self.data.len())?;
+        invariant::count("slot", live, free_slots, self.slots.len())
+    }
+
+    /// Mutable references to several values at once. Returns `None` if any
+    /// handle is stale, or if two handles refer to the same value.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, handles: [Handle; N])
+        -> Option<[&mut T; N]>
+    {
+        let mut addresses = [0; N];
+        for (i, &h) in handles.iter().enumerate() {
+            let slot = self.slots.get(h.slot)?;
+            let addr = match slot.address {
+                Entry::Taken { value } if slot.generation == h.generation => value,
+                _ => return None,
+            };
+            if addresses[..i].contains(&addr) {
+                return None;
+            }
+            addresses[i] = addr;
+        }
+
+        /* The addresses are taken and distinct, so the references never alias */
+        let data = self.data.as_mut_ptr();
+        Some(addresses.map(|a| match unsafe { &mut *data.add(a) } {
+            Entry::Taken { ref mut value } => value,
+            Entry::Free  { .. }            => panic!("corrupt free (data) list"),
+        }))
+    }
+
+    /// Write the map as a Graphviz graph: one node per value, labelled with
+    /// its slot, generation and `label`, and one edge per handle that
+    /// `edges` reports for it. The map has no roots of its own, so they are
+    /// passed in.
+    pub fn to_dot<F, E>(&self, w: &mut impl Write, mode: Mode, roots: &[Handle],
+        label: F, edges: E) -> io::Result<()>
+        where F: Fn(&T) -> String,
+              E: Fn(&T, &mut dyn FnMut(Handle)),
+    {
+        let len = self.slots.len();
+        let mut targets = vec![Vec::new(); len];
+        for (slot, targets) in targets.iter_mut().enumerate() {
+            if let Entry::Taken { value } = self.slots[slot].address {
+                if let Entry::Taken { ref value } = self.data[value] {
+                    edges(value, &mut |h| targets.push(h));
+                }
+            }
+        }
+
+        let live = |h: Handle| h.slot < len && self.get(h).is_some();
+        let live_roots: Vec<usize> = roots.iter().filter(|&&h| live(h)).map(|h| h.slot).collect();
+        let reachable = match mode {
+            Mode::Roots       => vec![true; len],
+            Mode::Unreachable => {
+                let slots: Vec<Vec<usize>> = targets.iter()
+                    .map(|t| t.iter().filter(|&&h| live(h)).map(|h| h.slot).collect())
+                    .collect();
+                dot::reachable(len, &live_roots, &slots)
+            },
+        };
+        let roots: HashSet<usize> = live_roots.into_iter().collect();
+
+        /* Stale handles get a node of their own, next to the live slots */
+        let mut graph = Graph::begin(w, "handlemap", "s")?;
+        let mut stale = HashMap::new();
+        for (slot, targets) in targets.iter().enumerate() {
+            let value = match self.slots[slot].address {
+                Entry::Taken { value } => match self.data[value] {
+                    Entry::Taken { ref value } => value,
+                    Entry::Free  { .. }        => panic!("corrupt free (data) list"),
+                },
+                Entry::Free { .. } => continue,
+            };
+            let text = format!("#{} gen {}\n{}", slot, self.slots[slot].generation, label(value));
+            graph.node(slot, &text, roots.contains(&slot), !reachable[slot])?;
+
+            for &h in targets {
+                if live(h) {
+                    graph.edge(slot, h.slot)?;
+                    continue;
+                }
+                let next = len + stale.len();
+                let id = *stale.entry((h.slot, h.generation)).or_insert(next);
+                if id == next {
+                    graph.dangling(id, &format!("#{} gen {} (stale)", h.slot, h.generation))?;
+                }
+                graph.edge(slot, id)?;
+            }
+        }
+        graph.end()
+    }
+
+    /* The address behind a valid handle; panics with the reason otherwise */
+    fn expect_address(&self, h: Handle) -> usize {
+        let slot = match self.slots.get(h.slot) {
+            Some(slot) => slot,
+            None => panic!("handle to slot {}, which was never allocated", h.slot),
+        };
+
+        if slot.generation != h.generation {
+            panic!("stale handle to slot {}: handle is generation {}, slot is generation {}",
+                h.slot, h.generation, slot.generation);
+        }
+
+        match slot.address {
+            Entry::Taken { value } => value,
+            Entry::Free  { .. }    => panic!("stale handle to slot {}: it was removed", h.slot),
+        }
+    }
+
+    /* The address behind a handle, if it is valid */
+    fn address(&self, h: Handle) -> Option<usize> {
+        match self.slots.get(h.slot)? {
+            &Slot { generation, address: Entry::Taken { value }, .. } if generation == h.generation
+                => Some(value),
+            _   => None,
+        }
+    }
+
+    /* Handles from another map may point past the slots */
     fn is_handle_valid(&self, h: Handle) -> bool {
-        self.slots[h.slot].generation == h.generation
+        self.slots.get(h.slot).is_some_and(|slot| slot.generation == h.generation)
+    }
+}
+
+
+impl<T> Index<Handle> for HandleMap2<T> {
+    type Output = T;
+
+    fn index(&self, h: Handle) -> &T {
+        match self.data[self.expect_address(h)] {
+            Entry::Taken { ref value } => value,
+            Entry::Free  { .. }        => panic!("corrupt free (data) list"),
+        }
     }
 }
 
+impl<T> IndexMut<Handle> for HandleMap2<T> {
+    fn index_mut(&mut self, h: Handle) -> &mut T {
+        let address = self.expect_address(h);
+        match self.data[address] {
+            Entry::Taken { ref mut value } => value,
+            Entry::Free  { .. }            => panic!("corrupt free (data) list"),
+        }
+    }
+}
 
 impl<T: fmt::Debug> fmt::Display for HandleMap2<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -187,3 +504,82 @@ impl<T: fmt::Debug> fmt::Display for HandleMap2<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handles() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        assert_eq!(m.remove(a), Some(1));
+        assert_eq!(m.remove(a), None);
+        let b = m.insert(2);
+        assert_eq!(m.get(a), None);
+        assert_eq!(m[b], 2);
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn disjoint_mut() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        if let Some([x, y]) = m.get_disjoint_mut([a, b]) {
+            ::std::mem::swap(x, y);
+        }
+        assert_eq!((m[a], m[b]), (2, 1));
+        assert!(m.get_disjoint_mut([a, a]).is_none());
+    }
+
+    #[test]
+    fn validate_finds_corruption() {
+        let mut m = HandleMap2::new();
+        m.insert(1);
+        m.insert(2);
+        m.slots[1].address = Entry::Taken { value: 0 };
+        assert_eq!(m.validate(), Err(Violation::Shared { slot: 1, address: 0 }));
+
+        let mut m = HandleMap2::new();
+        m.insert(1);
+        m.slots[0].address = Entry::Taken { value: 5 };
+        assert_eq!(m.validate(), Err(Violation::Dangling { slot: 0, address: 5 }));
+
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        m.remove(a);
+        m.remove(b);
+        m.slots[0].address = Entry::Free { next: Some(1) };
+        assert!(matches!(m.validate(), Err(Violation::Cycle { .. })));
+    }
+
+    #[test]
+    fn to_dot() {
+        let mut m: HandleMap2<(u32, Vec<Handle>)> = HandleMap2::new();
+        let gone = m.insert((0, Vec::new()));
+        m.remove(gone);
+        let leaf = m.insert((1, Vec::new()));
+        let root = m.insert((2, vec![leaf, gone]));
+        let lost = m.insert((3, vec![leaf]));
+
+        let mut out = Vec::new();
+        m.to_dot(&mut out, Mode::Unreachable, &[root], |v| v.0.to_string(),
+            |v, edge| v.1.iter().for_each(|&h| edge(h))).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+
+        let node = |h: Handle| format!("    s{} [label=\"#{} gen {}\\n", h.slot, h.slot, h.generation);
+        let line = |h: Handle| dot.lines().find(|l| l.starts_with(&node(h))).unwrap().to_string();
+        assert!(line(root).ends_with("style=bold, penwidth=2];"));
+        assert!(line(leaf).ends_with("\"];"));
+        assert!(line(lost).contains("fillcolor"));
+        assert_eq!(dot.matches("fillcolor").count(), 1);
+
+        /* The stale handle gets a node of its own */
+        assert!(dot.contains(&format!("#{} gen {} (stale)\", style=dashed", gone.slot, gone.generation)));
+        assert!(dot.contains(&format!("s{} -> s{};", root.slot, leaf.slot)));
+        assert!(dot.contains(&format!("s{} -> s{};", lost.slot, leaf.slot)));
+        assert_eq!(dot.matches("->").count(), 3);
+    }
+}
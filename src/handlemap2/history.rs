@@ -0,0 +1,392 @@
+/*
+ * Undo and redo.
+ *
+ * Every step is the journal of a transaction: the commands that invert its
+ * inserts, removes and changes. Undoing a step runs the commands, which
+ * gives the commands to redo it, and the other way around. Both put values
+ * back under the very same handles, while handles the undone step handed
+ * out stay stale until it is redone, whatever is inserted in between.
+ *
+ * Steps only keep commands, so their memory is easy to account for, but
+ * for what the values in them own on the heap, which a hook given to
+ * `set_limit_with` tells. When the history grows past its limit, the oldest
+ * steps to undo are forgotten first, then the furthest steps to redo. Compacting merges old steps into
+ * one, keeping a single command per handle.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::transaction::{Journal, Transaction};
+use super::{Handle, HandleMap2};
+
+/// A `HandleMap2` that remembers its changes, so they can be undone and
+/// redone.
+pub struct History<T: Clone> {
+    map:    HandleMap2<T>,
+    undo:   VecDeque<Journal<T>>,   /* oldest first */
+    redo:   VecDeque<Journal<T>>,   /* next to redo last */
+    bytes:  usize,                  /* kept by both */
+    limit:  usize,
+    heap:   fn(&T) -> usize,        /* what a value owns on the heap */
+}
+
+impl<T: Clone> History<T> {
+    /// A history starting at `map`, with no limit.
+    pub fn new(map: HandleMap2<T>) -> History<T> {
+        History {
+            map,
+            undo:   VecDeque::new(),
+            redo:   VecDeque::new(),
+            bytes:  0,
+            limit:  usize::MAX,
+            heap:   |_| 0,
+        }
+    }
+
+    pub fn map(&self) -> &HandleMap2<T> {
+        &self.map
+    }
+
+    pub fn into_map(self) -> HandleMap2<T> {
+        self.map
+    }
+
+    pub fn get(&self, h: Handle) -> Option<&T> {
+        self.map.get(h)
+    }
+
+    /// Apply changes through a transaction, as one step. If `f` panics, its
+    /// changes are rolled back and nothing is recorded.
+    pub fn apply<R, F>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Transaction<T>) -> R
+    {
+        let mut t = self.map.begin();
+        let r = f(&mut t);
+        let journal = t.finish();
+        self.record(journal);
+        r
+    }
+
+    pub fn insert(&mut self, t: T) -> Handle {
+        self.apply(|tx| tx.insert(t))
+    }
+
+    pub fn remove(&mut self, h: Handle) -> Option<T> {
+        self.apply(|tx| tx.remove(h))
+    }
+
+    /// Change the value behind `h`. Returns `None` if the handle is stale.
+    pub fn modify<R, F: FnOnce(&mut T) -> R>(&mut self, h: Handle, f: F) -> Option<R> {
+        self.apply(|tx| tx.get_mut(h).map(f))
+    }
+
+    /// Undo the last step. Returns `false` if there is none.
+    pub fn undo(&mut self) -> bool {
+        match self.undo.pop_back() {
+            Some(journal) => {
+                self.bytes -= journal.bytes(self.heap);
+                let inverse = journal.run(&mut self.map);
+                self.bytes += inverse.bytes(self.heap);
+                self.redo.push_back(inverse);
+                self.enforce_limit();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Redo the last undone step. Returns `false` if there is none, or if a
+    /// new step was recorded since.
+    pub fn redo(&mut self) -> bool {
+        match self.redo.pop_back() {
+            Some(journal) => {
+                self.bytes -= journal.bytes(self.heap);
+                let inverse = journal.run(&mut self.map);
+                self.bytes += inverse.bytes(self.heap);
+                self.undo.push_back(inverse);
+                self.enforce_limit();
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn undo_steps(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_steps(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Merge every step but the newest `keep` into one, which can only be
+    /// undone as a whole. Takes less memory when steps change the same
+    /// entries over and over.
+    pub fn compact(&mut self, keep: usize) {
+        if self.undo.len() <= keep + 1 {
+            return;
+        }
+        let old = self.undo.len() - keep;
+        let mut merged = self.undo.pop_front().expect("more steps than kept");
+        for _ in 1..old {
+            let newer = self.undo.pop_front().expect("more steps than kept");
+            merged = merged.merge(newer);
+        }
+        self.undo.push_front(merged);
+        self.recount();
+    }
+
+    /// Keep at most about `bytes` of history, forgetting the oldest steps to
+    /// undo when there is more, and then the furthest steps to redo. What
+    /// the values own on the heap is not counted, unless `set_limit_with`
+    /// said how.
+    pub fn set_limit(&mut self, bytes: usize) {
+        self.limit = bytes;
+        self.enforce_limit();
+    }
+
+    /// Like `set_limit`, also counting `heap(value)` bytes for every value
+    /// kept, e.g. `|s: &String| s.capacity()`.
+    pub fn set_limit_with(&mut self, bytes: usize, heap: fn(&T) -> usize) {
+        self.heap = heap;
+        self.recount();
+        self.set_limit(bytes);
+    }
+
+    /// Memory taken by the recorded steps, as counted for the limit.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Forget every step.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.bytes = 0;
+    }
+
+    fn record(&mut self, journal: Journal<T>) {
+        if journal.is_empty() {
+            return;
+        }
+        let heap = self.heap;
+        self.bytes -= self.redo.drain(..).map(|j| j.bytes(heap)).sum::<usize>();
+        self.bytes += journal.bytes(heap);
+        self.undo.push_back(journal);
+        self.enforce_limit();
+    }
+
+    /* Forget the steps furthest from the current state, undo steps first */
+    fn enforce_limit(&mut self) {
+        while self.bytes > self.limit {
+            match self.undo.pop_front().or_else(|| self.redo.pop_front()) {
+                Some(journal) => self.bytes -= journal.bytes(self.heap),
+                None          => break,
+            }
+        }
+    }
+
+    fn recount(&mut self) {
+        let heap = self.heap;
+        self.bytes = self.undo.iter().chain(self.redo.iter()).map(|j| j.bytes(heap)).sum();
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for History<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("History")
+            .field("map", &self.map)
+            .field("undo_steps", &self.undo.len())
+            .field("redo_steps", &self.redo.len())
+            .field("bytes", &self.bytes())
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_and_redo() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        h.modify(a, |v| *v = 2);
+        let b = h.insert(3);
+        assert_eq!(h.remove(a), Some(2));
+        assert_eq!(h.undo_steps(), 4);
+
+        assert!(h.undo());
+        assert_eq!(h.get(a), Some(&2));
+        assert!(h.undo());
+        assert_eq!(h.get(b), None);
+        assert!(h.undo());
+        assert_eq!(h.get(a), Some(&1));
+        assert!(h.undo());
+        assert_eq!(h.get(a), None);
+        assert!(!h.undo());
+
+        while h.redo() {}
+        assert_eq!(h.get(a), None);
+        assert_eq!(h.get(b), Some(&3));
+        assert_eq!(h.map().validate(), Ok(()));
+    }
+
+    #[test]
+    fn undone_handles_stay_stale() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        h.undo();
+        let b = h.insert(2);
+        assert_eq!(h.get(a), None);
+        assert_eq!(h.get(b), Some(&2));
+        assert!(!h.redo());
+
+        /* A slot freed and reused within the undone steps */
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        h.remove(a);
+        let b = h.insert(2);
+        h.undo();
+        h.undo();
+        assert_eq!(h.get(a), Some(&1));
+        h.remove(a);
+        let c = h.insert(3);
+        assert_eq!(h.get(b), None);
+        assert_eq!(h.get(c), Some(&3));
+        assert_eq!(h.map().validate(), Ok(()));
+    }
+
+    #[test]
+    fn redo_gives_back_the_same_handle() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        h.undo();
+        h.redo();
+        assert_eq!(h.get(a), Some(&1));
+    }
+
+    #[test]
+    fn panicking_step_is_not_recorded() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            h.apply(|t| {
+                t.remove(a);
+                panic!("step fails");
+            })
+        }));
+        assert!(r.is_err());
+        assert_eq!(h.get(a), Some(&1));
+        assert_eq!(h.undo_steps(), 1);
+    }
+
+    #[test]
+    fn limit_counts_redo_steps() {
+        let mut h = History::new(HandleMap2::new());
+        let handles: Vec<_> = (0..10).map(|i| h.insert(i)).collect();
+        while h.undo() {}
+        assert_eq!(h.redo_steps(), 10);
+
+        let full = h.bytes();
+        h.set_limit(full / 2);
+        assert!(h.bytes() <= full / 2);
+        assert!(h.redo_steps() < 10);
+
+        /* The nearest steps are the ones kept */
+        assert!(h.redo());
+        assert_eq!(h.get(handles[0]), Some(&0));
+    }
+
+    #[test]
+    fn limit_forgets_oldest_undo_steps() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(0);
+        for i in 1..10 {
+            h.modify(a, |v| *v = i);
+        }
+        let step = h.bytes() / 10;
+        h.set_limit(3 * step);
+        assert_eq!(h.undo_steps(), 3);
+        while h.undo() {}
+        assert_eq!(h.get(a), Some(&6));
+    }
+
+    #[test]
+    fn compact() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        let b = h.insert(2);
+        h.modify(a, |v| *v = 10);
+        h.modify(a, |v| *v = 100);
+        h.remove(b);
+        let c = h.insert(3);
+        let d = h.insert(4);
+        h.remove(d);
+        h.modify(c, |v| *v = 30);
+        let before = h.bytes();
+
+        h.compact(1);
+        assert_eq!(h.undo_steps(), 2);
+        assert!(h.bytes() < before);
+
+        h.undo();
+        assert_eq!(h.get(c), Some(&3));
+        h.undo();
+        assert_eq!(h.get(a), None);
+        assert_eq!(h.get(b), None);
+        assert_eq!(h.get(c), None);
+        assert_eq!(h.map().stats().live, 0);
+
+        h.redo();
+        assert_eq!(h.get(a), Some(&100));
+        assert_eq!(h.get(b), None);
+        assert_eq!(h.get(c), Some(&3));
+        assert_eq!(h.get(d), None);
+        h.redo();
+        assert_eq!(h.get(c), Some(&30));
+        assert_eq!(h.map().validate(), Ok(()));
+    }
+
+    #[test]
+    fn compact_keeps_handles_of_reused_slots() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(1);
+        h.remove(a);
+        let b = h.insert(2);
+        h.modify(b, |v| *v = 20);
+        h.compact(0);
+        assert_eq!(h.undo_steps(), 1);
+
+        h.undo();
+        assert_eq!(h.map().stats().live, 0);
+        h.redo();
+        assert_eq!(h.get(a), None);
+        assert_eq!(h.get(b), Some(&20));
+        assert_eq!(h.map().validate(), Ok(()));
+    }
+
+    #[test]
+    fn limit_counts_heap_values() {
+        let mut h = History::new(HandleMap2::new());
+        let a = h.insert(String::new());
+        for _ in 0..10 {
+            h.modify(a, |s| s.push_str(&"x".repeat(1000)));
+        }
+        let steps = h.bytes();
+
+        /* Each step keeps an old value of up to 9000 bytes */
+        h.set_limit_with(usize::MAX, String::capacity);
+        assert!(h.bytes() >= steps + 45000);
+        h.set_limit_with(steps + 20000, String::capacity);
+        assert!(h.bytes() <= steps + 20000);
+        assert!(h.undo_steps() < 10);
+
+        h.undo();
+        assert_eq!(h.get(a).map(String::len), Some(9000));
+    }
+}
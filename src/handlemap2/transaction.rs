@@ -0,0 +1,340 @@
+/*
+ * Transactions.
+ *
+ * A transaction borrows the map, and keeps a journal of the commands that
+ * undo its changes: removing what it inserted, putting back what it removed
+ * under the same handle, and swapping old values back into what it changed
+ * (cloned the first time it changes them). Rolling back runs the journal
+ * backwards, so handles from before the transaction stay valid.
+ *
+ * Each command undoes its change exactly, so rolling back restores the
+ * free lists, the slot generations and the data as they were, with one
+ * exception: slots the transaction appended stay, free. Undoing never
+ * lowers the highest generation a slot handed out, so a handle issued
+ * during the transaction stays stale after rolling back, whatever is
+ * inserted next.
+ *
+ * Running a journal returns the journal that undoes the run, which is how
+ * the history redoes what it undid.
+ */
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::mem;
+
+use super::{Handle, HandleMap2, Prior};
+
+/// Changes to a `HandleMap2` that are applied all together, or not at all.
+///
+/// Dropping a transaction without committing it rolls it back.
+pub struct Transaction<'a, T: Clone + 'a> {
+    map:      &'a mut HandleMap2<T>,
+    journal:  Option<Journal<T>>,     /* taken when committed or rolled back */
+    modified: HashSet<(usize, usize)>, /* handles with their old value in the journal */
+}
+
+/* Commands that undo changes to a map, in the order of the changes */
+pub(super) struct Journal<T> {
+    commands: Vec<Command<T>>,
+}
+
+/* Undoes one change; running it gives the command that redoes it */
+pub(super) enum Command<T> {
+    Insert { handle: Handle, value: T },        /* put back a removed value */
+    Remove { handle: Handle, prior: Prior },    /* take out an inserted value */
+    Modify { handle: Handle, value: T },        /* swap in the old value */
+}
+
+impl<T: Clone> HandleMap2<T> {
+    /// Start a transaction. Values are cloned the first time the transaction
+    /// changes them, so it can roll back.
+    pub fn begin(&mut self) -> Transaction<'_, T> {
+        Transaction { map: self, journal: Some(Journal::new()), modified: HashSet::new() }
+    }
+}
+
+impl<'a, T: Clone> Transaction<'a, T> {
+    pub fn insert(&mut self, t: T) -> Handle {
+        let (handle, prior) = self.map.insert_undoable(t);
+        self.journal().commands.push(Command::Remove { handle, prior });
+        handle
+    }
+
+    pub fn remove(&mut self, h: Handle) -> Option<T> {
+        let value = self.map.remove(h)?;
+        self.journal().commands.push(Command::Insert { handle: h, value: value.clone() });
+        Some(value)
+    }
+
+    pub fn get(&self, h: Handle) -> Option<&T> {
+        self.map.get(h)
+    }
+
+    pub fn get_mut(&mut self, h: Handle) -> Option<&mut T> {
+        self.map.address(h)?;
+        /* A handle never comes back once removed, so its first old value
+         * is the one to restore */
+        if self.modified.insert((h.slot, h.generation)) {
+            let value = self.map.get(h).expect("handle is valid").clone();
+            self.journal().commands.push(Command::Modify { handle: h, value });
+        }
+        self.map.get_mut(h)
+    }
+
+    /// Keep the changes.
+    pub fn commit(mut self) {
+        self.journal = None;
+    }
+
+    /// Undo every change made through the transaction.
+    pub fn rollback(mut self) {
+        if let Some(journal) = self.journal.take() {
+            journal.run(self.map);
+        }
+    }
+
+    /* Keep the changes, and what it takes to undo them */
+    pub(super) fn finish(mut self) -> Journal<T> {
+        self.journal.take().expect("transaction is over")
+    }
+
+    fn journal(&mut self) -> &mut Journal<T> {
+        self.journal.as_mut().expect("transaction is over")
+    }
+}
+
+impl<'a, T: Clone> Drop for Transaction<'a, T> {
+    fn drop(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            journal.run(self.map);
+        }
+    }
+}
+
+impl<'a, T: Clone + fmt::Debug> fmt::Debug for Transaction<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("map", &self.map)
+            .field("open", &self.journal.is_some())
+            .finish()
+    }
+}
+
+impl<T> Command<T> {
+    fn handle(&self) -> Handle {
+        match *self {
+            Command::Insert { handle, .. } | Command::Remove { handle, .. } | Command::Modify { handle, .. }
+                => handle,
+        }
+    }
+
+    fn run(self, map: &mut HandleMap2<T>) -> Command<T> {
+        match self {
+            Command::Insert { handle, value } => {
+                let prior = map.insert_at(handle, value);
+                Command::Remove { handle, prior }
+            },
+            Command::Remove { handle, prior } => {
+                let value = map.uninsert(handle, prior);
+                Command::Insert { handle, value }
+            },
+            Command::Modify { handle, value } => {
+                let old = mem::replace(map.get_mut(handle).expect("journaled handle is stale"), value);
+                Command::Modify { handle, value: old }
+            },
+        }
+    }
+}
+
+impl<T> Journal<T> {
+    fn new() -> Journal<T> {
+        Journal { commands: Vec::new() }
+    }
+
+    /// Undo the journaled changes, last first. Returns the journal that
+    /// undoes this.
+    pub(super) fn run(self, map: &mut HandleMap2<T>) -> Journal<T> {
+        let commands = self.commands.into_iter().rev().map(|c| c.run(map)).collect();
+        Journal { commands }
+    }
+
+    /* One journal for this one and a `newer` one, which began where this
+     * one ended, with one command per handle for its net change. A handle
+     * is only ever inserted first and removed last, so that is: nothing
+     * for one that came and went, removing one that came, putting back one
+     * that went, or its oldest value. */
+    pub(super) fn merge(self, newer: Journal<T>) -> Journal<T> {
+        let mut net = BTreeMap::new();
+        for command in self.commands.into_iter().chain(newer.commands) {
+            let h = command.handle();
+            let key = (h.slot, h.generation);
+            let merged = match (net.remove(&key), command) {
+                (None, command)                                          => command,
+                (Some(Command::Remove { .. }), Command::Insert { .. })   => continue,
+                (Some(Command::Modify { handle, value }), Command::Insert { .. })
+                    => Command::Insert { handle, value },
+                (Some(Command::Insert { .. }), _)                        => panic!("removed handle came back"),
+                (Some(older), _)                                         => older,
+            };
+            net.insert(key, merged);
+        }
+
+        /* Undoing runs last first: taking out the new values must come
+         * before putting back the old ones, which may want their slots */
+        let (mut commands, removes): (Vec<_>, Vec<_>) = net.into_values()
+            .partition(|c| !matches!(c, Command::Remove { .. }));
+        commands.extend(removes);
+        Journal { commands }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /* Memory kept, with `heap` telling what a value owns on the heap */
+    pub(super) fn bytes(&self, heap: fn(&T) -> usize) -> usize {
+        let values: usize = self.commands.iter()
+            .map(|c| match *c {
+                Command::Insert { ref value, .. } | Command::Modify { ref value, .. } => heap(value),
+                Command::Remove { .. }                                               => 0,
+            })
+            .sum();
+        mem::size_of::<Journal<T>>() + self.commands.len() * mem::size_of::<Command<T>>() + values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = {
+            let mut t = m.begin();
+            *t.get_mut(a).unwrap() = 10;
+            let b = t.insert(2);
+            t.commit();
+            b
+        };
+        assert_eq!(m.get(a), Some(&10));
+        assert_eq!(m.get(b), Some(&2));
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rollback_restores_values_and_handles() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        let c = m.insert(3);
+
+        let mut t = m.begin();
+        *t.get_mut(a).unwrap() = 10;
+        *t.get_mut(a).unwrap() = 100;
+        assert_eq!(t.remove(b), Some(2));
+        t.insert(4);
+        t.remove(c);
+        t.rollback();
+
+        assert_eq!(m.get(a), Some(&1));
+        assert_eq!(m.get(b), Some(&2));
+        assert_eq!(m.get(c), Some(&3));
+        assert_eq!(m.stats().live, 3);
+        assert_eq!(m.data.len(), 3);
+        assert_eq!(m.free_data_head, None);
+        assert_eq!(m.free_slot_head, None);
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn drop_rolls_back() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        {
+            let mut t = m.begin();
+            t.remove(a);
+        }
+        assert_eq!(m.get(a), Some(&1));
+    }
+
+    #[test]
+    fn rolled_back_handles_stay_stale() {
+        /* A new slot */
+        let mut m = HandleMap2::new();
+        let mut t = m.begin();
+        let h = t.insert(99);
+        t.rollback();
+        assert_eq!(m.get(h), None);
+        assert_eq!(m.data.len(), 0);
+        assert_eq!(m.free_data_head, None);
+        assert_eq!(m.free_slot_head, Some(h.slot)); /* kept, to stay stale */
+        let n = m.insert(7);
+        assert_eq!(m.get(n), Some(&7));
+        assert_eq!(m.get(h), None);
+
+        /* A reused slot */
+        let mut m = HandleMap2::new();
+        let old = m.insert(1);
+        m.remove(old);
+        let (data_head, slot_head) = (m.free_data_head, m.free_slot_head);
+        let mut t = m.begin();
+        let h = t.insert(99);
+        t.rollback();
+        assert_eq!((m.free_data_head, m.free_slot_head), (data_head, slot_head));
+        assert_eq!(m.slots[h.slot].generation, old.generation);
+        let n = m.insert(7);
+        assert_eq!(m.get(n), Some(&7));
+        assert_eq!(m.get(h), None);
+        assert_eq!(m.get(old), None);
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rollback_after_slot_reuse() {
+        /* b's slot is handed out again inside the transaction */
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        let mut t = m.begin();
+        t.remove(b);
+        let c = t.insert(3);
+        t.rollback();
+
+        assert_eq!(m.get(a), Some(&1));
+        assert_eq!(m.get(b), Some(&2));
+        assert_eq!(m.data.len(), 2);
+        assert_eq!(m.free_data_head, None);
+        assert_eq!(m.free_slot_head, None);
+        m.remove(b);
+        let d = m.insert(4);
+        assert_eq!(m.get(c), None);
+        assert_eq!(m.get(d), Some(&4));
+        assert_eq!(m.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rollback_drops_appended_data() {
+        /* Some memory is free before, and more is appended */
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        m.remove(a);
+        let (data_head, slot_head) = (m.free_data_head, m.free_slot_head);
+
+        let mut t = m.begin();
+        let c = t.insert(3);
+        let d = t.insert(4);
+        t.remove(b);
+        t.rollback();
+
+        assert_eq!(m.data.len(), 2);
+        assert_eq!(m.free_data_head, data_head);
+        assert_eq!(m.slots[slot_head.unwrap()].generation, a.generation);
+        assert_eq!(m.get(b), Some(&2));
+        assert_eq!(m.get(c), None);
+        assert_eq!(m.get(d), None);
+        assert_eq!(m.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt;
+
+/* Validate a container after every mutation, but only in debug builds with
+ * the `check_invariants` feature; otherwise this compiles to nothing. */
+macro_rules! debug_validate {
+    ($container:expr) => {
+        if cfg!(all(debug_assertions, feature = "check_invariants")) {
+            if let Err(e) = $container.validate() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+/// The first broken invariant found by a `validate()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A free list links to an index outside of the storage.
+    OutOfBounds { list: &'static str, index: usize },
+    /// A free list visits the same index twice.
+    Cycle       { list: &'static str, index: usize },
+    /// A free list runs into an entry that is in use.
+    CrossLink   { list: &'static str, index: usize },
+    /// Free entries plus live entries do not add up to the capacity.
+    Count       { list: &'static str, live: usize, free: usize, capacity: usize },
+    /// A slot refers to data that is not in use, or does not exist.
+    Dangling    { slot: usize, address: usize },
+    /// Two slots refer to the same data.
+    Shared      { slot: usize, address: usize },
+    /// Two variable-sized blocks overlap.
+    Overlap     { list: &'static str, offset: usize },
+    /// Two neighbouring free blocks were not merged.
+    Uncoalesced { list: &'static str, offset: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::OutOfBounds { list, index } =>
+                write!(f, "corrupt free ({}) list: link to {} is out of bounds", list, index),
+            Violation::Cycle { list, index } =>
+                write!(f, "corrupt free ({}) list: {} is visited twice", list, index),
+            Violation::CrossLink { list, index } =>
+                write!(f, "corrupt free ({}) list: {} is in use", list, index),
+            Violation::Count { list, live, free, capacity } =>
+                write!(f, "corrupt free ({}) list: {} live + {} free != {} capacity",
+                    list, live, free, capacity),
+            Violation::Dangling { slot, address } =>
+                write!(f, "slot {} refers to unused address {}", slot, address),
+            Violation::Shared { slot, address } =>
+                write!(f, "slot {} refers to address {}, which is already referred to",
+                    slot, address),
+            Violation::Overlap { list, offset } =>
+                write!(f, "corrupt {} blocks: block at {} overlaps the one before", list, offset),
+            Violation::Uncoalesced { list, offset } =>
+                write!(f, "corrupt free ({}) list: {} was not merged with the block before",
+                    list, offset),
+        }
+    }
+}
+
+impl Error for Violation {}
+
+/// Walk an intrusive free list of `len` entries starting at `head`.
+///
+/// `next` returns the link of a free entry, or `None` if the entry is in
+/// use. Returns which entries are on the list.
+pub(crate) fn walk<F>(list: &'static str, head: Option<usize>, len: usize, mut next: F)
+    -> Result<Vec<bool>, Violation>
+    where F: FnMut(usize) -> Option<Option<usize>>
+{
+    let mut visited = vec![false; len];
+    let mut cursor = head;
+
+    while let Some(i) = cursor {
+        if i >= len {
+            return Err(Violation::OutOfBounds { list, index: i });
+        }
+        if visited[i] {
+            return Err(Violation::Cycle { list, index: i });
+        }
+        visited[i] = true;
+
+        cursor = match next(i) {
+            Some(link) => link,
+            None       => return Err(Violation::CrossLink { list, index: i }),
+        };
+    }
+
+    Ok(visited)
+}
+
+/// Check that a stack of free indices is in bounds and has no repeats.
+pub(crate) fn walk_stack(list: &'static str, stack: &[usize], len: usize)
+    -> Result<Vec<bool>, Violation>
+{
+    let mut visited = vec![false; len];
+
+    for &i in stack {
+        if i >= len {
+            return Err(Violation::OutOfBounds { list, index: i });
+        }
+        if visited[i] {
+            return Err(Violation::Cycle { list, index: i });
+        }
+        visited[i] = true;
+    }
+
+    Ok(visited)
+}
+
+/// Check that `live` and `free` entries account for all of `capacity`.
+pub(crate) fn count(list: &'static str, live: usize, free: usize, capacity: usize)
+    -> Result<(), Violation>
+{
+    if live + free == capacity {
+        Ok(())
+    } else {
+        Err(Violation::Count { list, live, free, capacity })
+    }
+}
@@ -1,5 +1,21 @@
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+
+extern crate pile_derive;
+
+#[macro_use]
+pub mod invariant;
+pub mod stats;
+pub mod dot;
 pub mod handlemap;
 pub mod handlemap2;
 pub mod freelist;
 pub mod slotmap;
 pub mod pile;
+pub mod mixed;
+pub mod sizeclass;
+pub mod slab;
+pub mod buddy;
+pub mod tlsf;
+pub mod arena;
+#[cfg(feature = "nightly")]
+pub mod allocator;
@@ -8,14 +8,14 @@ fn fl() {
     let y = fl.insert(35);
     let z = fl.insert(73);
 
-    let t = fl.remove(x);
+    let _t = fl.remove(x);
 
     fl.remove(x); /* multiple calls to remove is ok */
 
     fl.remove(z);
 
-    let u = fl.insert(42);
-    let v = fl.insert(69);
+    let _u = fl.insert(42);
+    let _v = fl.insert(69);
 
 
     let a = fl.get(x); /* Danger! These are never invalidated. */
@@ -0,0 +1,325 @@
+//! A pile holding objects of many types at once.
+//!
+//! Every object is boxed as an `Object` trait object in a single `Pile`, so
+//! the collector traces across types: a `Pointer<Closure>` inside a `Cons`
+//! keeps the closure alive like any other pointer. Pointers and roots carry
+//! the type of their object, and are checked against it when an untyped
+//! pointer is turned into a typed one.
+
+use std::any::Any;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use invariant::Violation;
+use pile::{self, Pile, PileRef, PileRefMut, Trace};
+use stats::Stats;
+
+/// A value that can live in a `MixedPile`: anything traceable.
+pub trait Object: Trace + 'static {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Trace + 'static> Object for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A pointer to an object of type `T` in a `MixedPile`.
+pub struct Pointer<T> {
+    raw:     pile::Pointer,
+    _object: PhantomData<fn() -> T>,
+}
+
+/// A typed root; see `pile::Root`.
+pub struct Root<T> {
+    root:    pile::Root<Box<dyn Object>>,
+    _object: PhantomData<fn() -> T>,
+}
+
+/// A shared borrow of an object of type `T`.
+pub struct Ref<'a, T> {
+    object:  PileRef<'a, Box<dyn Object>>,
+    _object: PhantomData<&'a T>,
+}
+
+/// An exclusive borrow of an object of type `T`. Applies the write barrier
+/// when dropped, like `PileRefMut`.
+pub struct RefMut<'a, T> {
+    object:  PileRefMut<'a, Box<dyn Object>>,
+    _object: PhantomData<&'a mut T>,
+}
+
+#[derive(Default)]
+pub struct MixedPile {
+    pile: Pile<Box<dyn Object>>,
+}
+
+impl MixedPile {
+    pub fn new() -> MixedPile {
+        MixedPile { pile: Pile::new() }
+    }
+
+    pub fn with_capacity(n: usize) -> MixedPile {
+        MixedPile { pile: Pile::with_capacity(n) }
+    }
+
+    pub fn alloc<T: Object>(&mut self, t: T) -> Root<T> {
+        Root {
+            root:    self.pile.alloc(Box::new(t)),
+            _object: PhantomData,
+        }
+    }
+
+    /// Root the object at `p`. Returns `None` if it was freed, or its handle
+    /// now holds an object of another type.
+    pub fn root<T: Object>(&mut self, p: Pointer<T>) -> Option<Root<T>> {
+        if !self.is::<T>(p.raw) {
+            return None;
+        }
+        Some(Root {
+            root:    self.pile.root(p.raw)?,
+            _object: PhantomData,
+        })
+    }
+
+    pub fn get<T: Object>(&self, p: Pointer<T>) -> Option<Ref<'_, T>> {
+        let object = self.pile.get(p.raw)?;
+        if !(**object).as_any().is::<T>() {
+            return None;
+        }
+        Some(Ref { object, _object: PhantomData })
+    }
+
+    pub fn get_mut<T: Object>(&mut self, p: Pointer<T>) -> Option<RefMut<'_, T>> {
+        if !self.is::<T>(p.raw) {
+            return None;
+        }
+        let object = self.pile.get_mut(p.raw)?;
+        Some(RefMut { object, _object: PhantomData })
+    }
+
+    /// Whether `p` points to an object of type `T`.
+    pub fn is<T: Object>(&self, p: pile::Pointer) -> bool {
+        match self.pile.get(p) {
+            Some(object) => (**object).as_any().is::<T>(),
+            None         => false,
+        }
+    }
+
+    /// The typed pointer, if `p` points to an object of type `T`.
+    pub fn downcast<T: Object>(&self, p: pile::Pointer) -> Option<Pointer<T>> {
+        if self.is::<T>(p) {
+            Some(Pointer { raw: p, _object: PhantomData })
+        } else {
+            None
+        }
+    }
+
+    pub fn free<T>(&mut self, p: Pointer<T>) {
+        self.pile.free(p.raw)
+    }
+
+    /// See `Pile::write_barrier`. Only needed after storing pointers through
+    /// interior mutability; `RefMut` applies it by itself.
+    pub fn write_barrier<A, B>(&mut self, holder: Pointer<A>, stored: Pointer<B>) {
+        self.pile.write_barrier(holder.raw, stored.raw)
+    }
+
+    pub fn collect(&mut self) -> usize {
+        self.pile.collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.pile.stats()
+    }
+
+    pub fn validate(&self) -> Result<(), Violation> {
+        self.pile.validate()
+    }
+
+    /// The underlying pile, for everything else it offers (incremental
+    /// collection, triggers, finalizers, dumps, ...).
+    pub fn as_pile(&self) -> &Pile<Box<dyn Object>> {
+        &self.pile
+    }
+
+    pub fn as_pile_mut(&mut self) -> &mut Pile<Box<dyn Object>> {
+        &mut self.pile
+    }
+}
+
+impl<T> Pointer<T> {
+    /// The untyped pointer, e.g. to keep pointers to different types in
+    /// one collection.
+    pub fn erase(self) -> pile::Pointer {
+        self.raw
+    }
+}
+
+impl<T> Root<T> {
+    pub fn pointer(&self) -> Pointer<T> {
+        Pointer {
+            raw:     self.root.pointer(),
+            _object: PhantomData,
+        }
+    }
+}
+
+/* Manual impls, so they do not require anything of T */
+
+impl<T> Clone for Pointer<T> {
+    fn clone(&self) -> Pointer<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Pointer<T> {}
+
+impl<T> PartialEq for Pointer<T> {
+    fn eq(&self, other: &Pointer<T>) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<T> Eq for Pointer<T> {}
+
+impl<T> Hash for Pointer<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.raw.hash(state)
+    }
+}
+
+impl<T> fmt::Debug for Pointer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pointer<{}>({:?})", ::std::any::type_name::<T>(), self.raw)
+    }
+}
+
+impl<T> Trace for Pointer<T> {
+    fn trace(&self, tracer: &mut dyn FnMut(pile::Pointer)) {
+        tracer(self.raw)
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut pile::Pointer)) {
+        tracer(&mut self.raw)
+    }
+}
+
+impl<T> Clone for Root<T> {
+    fn clone(&self) -> Root<T> {
+        Root {
+            root:    self.root.clone(),
+            _object: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Root<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Root({:?})", self.pointer())
+    }
+}
+
+impl fmt::Debug for MixedPile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MixedPile({:?})", self.pile.stats())
+    }
+}
+
+impl<'a, T: Object> Deref for Ref<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        (**self.object).as_any().downcast_ref().expect("type checked when borrowed")
+    }
+}
+
+impl<'a, T: Object> Deref for RefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        (**self.object).as_any().downcast_ref().expect("type checked when borrowed")
+    }
+}
+
+impl<'a, T: Object> DerefMut for RefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        (**self.object).as_any_mut().downcast_mut().expect("type checked when borrowed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A cons cell holding a string of another type */
+    type Cons = (u32, Option<Pointer<String>>);
+
+    #[test]
+    fn reachable_across_types() {
+        let mut pile = MixedPile::new();
+        let s = pile.alloc(String::from("kept")).pointer();
+        let cons = pile.alloc::<Cons>((1, Some(s)));
+        let lost_string = pile.alloc(String::from("lost")).pointer();
+        let lost_cons = pile.alloc::<Cons>((2, None)).pointer();
+
+        assert_eq!(pile.collect(), 2);
+        assert_eq!(*pile.get(s).unwrap(), "kept");
+        assert_eq!(pile.get(cons.pointer()).unwrap().1, Some(s));
+        assert!(pile.get(lost_string).is_none());
+        assert!(pile.get(lost_cons).is_none());
+
+        drop(cons);
+        assert_eq!(pile.collect(), 2);
+        assert_eq!(pile.stats().live, 0);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn stored_through_ref_mut() {
+        let mut pile = MixedPile::new();
+        let cons = pile.alloc::<Cons>((1, None));
+        let s = pile.alloc(String::from("late")).pointer();
+        pile.get_mut(cons.pointer()).unwrap().1 = Some(s);
+
+        assert_eq!(pile.collect(), 0);
+        assert_eq!(*pile.get(s).unwrap(), "late");
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut pile = MixedPile::new();
+        let s = pile.alloc(String::from("s")).pointer();
+        let raw = s.erase();
+        assert!(pile.is::<String>(raw));
+        assert!(!pile.is::<u32>(raw));
+        assert!(pile.downcast::<u32>(raw).is_none());
+        assert_eq!(pile.downcast::<String>(raw), Some(s));
+    }
+
+    #[test]
+    fn freed_or_reused() {
+        let mut pile = MixedPile::new();
+        let s = pile.alloc(String::from("s")).pointer();
+        pile.free(s);
+        assert!(pile.get(s).is_none());
+        assert!(pile.get_mut(s).is_none());
+        assert!(pile.root(s).is_none());
+
+        /* The handle now holds an object of another type */
+        let n = pile.alloc(7u32);
+        assert_eq!(n.pointer().erase(), s.erase());
+        assert!(pile.get(s).is_none());
+        assert!(pile.root(s).is_none());
+        assert!(pile.downcast::<String>(s.erase()).is_none());
+        assert_eq!(*pile.get(n.pointer()).unwrap(), 7);
+    }
+}
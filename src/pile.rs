@@ -1,17 +1,65 @@
-
-use std::cell::{RefCell, Ref, RefMut};
-use std::rc::{Rc};
-use std::fmt;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
 use std::mem;
 
-pub enum Colour { 
-    White, 
-    Black
+use invariant::{self, Violation};
+use stats::{self, Counters, Stats};
+
+mod bytes;
+mod collect;
+mod dump;
+mod ephemeron;
+mod finalize;
+mod guard;
+mod nursery;
+mod root;
+mod snapshot;
+mod trigger;
+mod weak;
+
+pub use pile_derive::Trace;
+pub use self::bytes::Fit;
+pub use self::ephemeron::EphemeronMap;
+pub use self::guard::{PileRef, PileRefMut};
+pub use self::root::Root;
+pub use self::snapshot::{Persist, Remap};
+pub use self::trigger::{Collection, Reason, Trigger};
+pub use self::weak::WeakPointer;
+
+use self::bytes::Bytes;
+use self::collect::Phase;
+use self::ephemeron::Table;
+use self::finalize::Finalizers;
+use self::root::RootSet;
+use self::trigger::Auto;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colour {
+    White,  /* not (yet) known to be reachable */
+    Grey,   /* reachable, but its pointers are not traced yet */
+    Black   /* reachable, and all its pointers are traced */
 }
 
-pub trait Collectable {
-    fn mark(&mut self, col: Colour);
-    fn get_colour(self);
+/// An object that can live in a `Pile`.
+///
+/// The collector needs to know which parts of an object are pointers,
+/// so every object must be able to hand them over to a visitor. Leaf
+/// types (integers, strings, ...) simply visit nothing.
+///
+/// Forgetting a field here frees objects that are still in use, so prefer
+/// `#[derive(Trace)]`, which visits every field not marked `#[trace(skip)]`.
+pub trait Trace {
+    /// Visit every `Pointer` held by the object.
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer));
+
+    /// Visit every `Pointer` held by the object, allowing the visitor to
+    /// redirect it (used when the heap is compacted).
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer));
+
+    /// Visit every `WeakPointer` held by the object, allowing the visitor to
+    /// redirect it. Only objects holding weak pointers need to implement it.
+    fn trace_weak_mut(&mut self, _: &mut dyn FnMut(&mut WeakPointer)) {}
 }
 
 
@@ -19,95 +67,210 @@ pub trait Collectable {
 const DEFAULT_CAPACITY: usize = 8;
 
 #[derive(Debug)]
-pub struct Pile {
-    memory:         Vec<Entry<u8>>,
+pub struct Pile<T> {
+    memory:         Vec<Entry<T>>,
     handles:        Vec<Handle>,
+    generations:    Vec<u32>,  /* per handle, bumped when it is freed */
     free_head:      Option<usize>,
     handle_head:    Option<usize>,
     allocated:      usize,
+    nursery:        Vec<Entry<T>>,
+    nursery_size:   usize,
+    remembered:     HashSet<usize>,
+    roots:          Rc<RefCell<RootSet>>,
+    phase:          Phase,
+    grey:           Vec<usize>,
+    auto:           Auto,
+    finalizers:     Finalizers<T>,
+    ephemerons:     Vec<Table>,
+    bytes:          Bytes,
+    counters:       Counters,
 }
 
-type PileReference<T> = Rc<RefCell<T>>;
-
 #[derive(Debug)]
 enum Entry<T> {
     Free    { next:     Option<usize> },
-    Value   { value:    Rc<RefCell<T>> },
+    Value   { value:    T, colour: Colour },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Handle {
     Unused  { next: Option<usize> },
-    Used    { addr: usize },
+    Used    { addr: usize }, /* index in memory */
+    Young   { addr: usize }, /* index in the nursery */
+    Bytes,                   /* a block in the byte region */
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Pointer {
     handle:   usize,
 }
 
-impl Pile {
-    pub fn new() -> Pile {
+impl<T> Default for Pile<T> {
+    fn default() -> Pile<T> {
+        Pile::new()
+    }
+}
+
+impl<T> Pile<T> {
+    pub fn new() -> Pile<T> {
         // Create a new pile with the default size
         Pile::with_capacity(DEFAULT_CAPACITY)
     }
 
-    pub fn with_capacity(n: usize) -> Pile {
+    pub fn with_capacity(n: usize) -> Pile<T> {
         // Creates a new pile with a specific capacity n.
+        Pile::with_nursery(n, 0)
+    }
+
+    pub fn with_nursery(n: usize, nursery: usize) -> Pile<T> {
+        // Creates a pile with capacity n, that allocates new objects in a
+        // nursery of the given size until they survive a minor collection.
         let mut pile = Pile {
             memory:         Vec::new(),
             handles:        Vec::new(),
+            generations:    Vec::new(),
             free_head:      None,
             handle_head:    None,
             allocated:      0,
+            nursery:        Vec::with_capacity(nursery),
+            nursery_size:   nursery,
+            remembered:     HashSet::new(),
+            roots:          Rc::default(),
+            phase:          Phase::Idle,
+            grey:           Vec::new(),
+            auto:           Auto::new(Trigger::manual()),
+            finalizers:     Finalizers::new(),
+            ephemerons:     Vec::new(),
+            bytes:          Bytes::new(),
+            counters:       Counters::new(),
         };
         pile.reserve(n);
         pile.reserve_handles(n);
         pile
     }
 
-    pub fn alloc(&mut self, t: u8) -> Pointer {
-        match self.try_alloc(t) {
-            Ok(ptr) => ptr,
-            Err(u)  => self.grow_and_alloc(u), // u is t, back from try_alloc
+    /// Root an object that is already in the pile, e.g. one found through
+    /// another object. Returns `None` if `p` does not point to an object.
+    pub fn root(&mut self, p: Pointer) -> Option<Root<T>> {
+        self.colour(p.handle)?;
+
+        /* A root is a pointer store too; keep an ongoing marking sound */
+        if self.phase == Phase::Mark {
+            self.shade(p.handle);
         }
+        Some(Root::new(p, &self.roots))
     }
 
+    /// Free an object by hand. Freeing an object that is still reachable,
+    /// or still rooted, leaves dangling pointers behind. The object's
+    /// finalizer, if any, is dropped without running.
     pub fn free(&mut self, p: Pointer) {
-        let address: usize = match self.handles[p.handle] {
-            Handle::Used { addr}  => addr,
-            Handle::Unused { .. } => return,
+        let handle = mem::replace(
+            &mut self.handles[p.handle],
+            Handle::Unused { next: self.handle_head },
+        );
+
+        let address: usize = match handle {
+            Handle::Used { addr }  => addr,
+            Handle::Young { addr } => {
+                // leave a hole; the nursery is emptied all at once
+                self.nursery[addr] = Entry::Free { next: None };
+                self.handle_head = Some(p.handle);
+                self.generations[p.handle] = self.generations[p.handle].wrapping_add(1);
+                self.finalizers.forget(p.handle);
+                self.allocated -= 1;
+                self.counters.remove();
+                debug_validate!(self);
+                return;
+            },
+            Handle::Bytes          => {
+                self.handle_head = Some(p.handle);
+                self.generations[p.handle] = self.generations[p.handle].wrapping_add(1);
+                self.free_bytes(p.handle);
+                debug_validate!(self);
+                return;
+            },
+            Handle::Unused { .. }  => {
+                self.handles[p.handle] = handle;
+                return;
+            },
         };
 
+        // give the handle back, so it can be reused
+        self.handle_head = Some(p.handle);
+        self.generations[p.handle] = self.generations[p.handle].wrapping_add(1);
+        self.finalizers.forget(p.handle);
+
         // replace the element with a free block
-        mem::replace(
+        let old = mem::replace(
             &mut self.memory[address],
             Entry::Free {
                 next: self.free_head,
             },
         );
 
-        self.free_head = Some(address);
-        self.allocated -= 1;
+        match old {
+            Entry::Value { .. } => {
+                self.free_head = Some(address);
+                self.allocated -= 1;
+                self.counters.remove();
+            },
+            // was already free; put it back to preserve the list
+            e @ Entry::Free { .. } => self.memory[address] = e,
+        }
+        debug_validate!(self);
     }
 
-    pub fn get(&self, p: Pointer) -> Option<PileReference<u8>> {
-        let address: usize = match self.handles[p.handle] {
-            Handle::Used { addr}  => addr,
-            Handle::Unused { .. } => return None,
-        };
-        
-        match self.memory.get(address) {
-            Some(Entry::Value { value }) => Some(value.clone()),
-            _                            => None
+    pub fn get(&self, p: Pointer) -> Option<PileRef<'_, T>> {
+        match self.cell(p.handle) {
+            Some(Entry::Value { value, .. }) => Some(PileRef::new(value)),
+            _                                => None
         }
+    }
 
+    /// Move the objects in memory to its start, in handle order, and give
+    /// the free memory back. Pointers stay valid, since only the handles
+    /// change.
+    pub fn compact(&mut self) {
+        let mut memory = Vec::with_capacity(self.allocated - self.young());
+        for handle in &mut self.handles {
+            if let Handle::Used { ref mut addr } = *handle {
+                let entry = mem::replace(&mut self.memory[*addr], Entry::Free { next: None });
+                *addr = memory.len();
+                memory.push(entry);
+            }
+        }
+
+        self.memory = memory;
+        self.free_head = None;
+        debug_validate!(self);
+    }
+
+    /* The cell a used handle refers to, in either region */
+    fn cell(&self, handle: usize) -> Option<&Entry<T>> {
+        match self.handles.get(handle) {
+            Some(&Handle::Used { addr })  => Some(&self.memory[addr]),
+            Some(&Handle::Young { addr }) => Some(&self.nursery[addr]),
+            _                             => None,
+        }
+    }
+
+    fn cell_mut(&mut self, handle: usize) -> Option<&mut Entry<T>> {
+        match self.handles.get(handle) {
+            Some(&Handle::Used { addr })  => Some(&mut self.memory[addr]),
+            Some(&Handle::Young { addr }) => Some(&mut self.nursery[addr]),
+            _                             => None,
+        }
     }
 
     pub fn reserve(&mut self, n: usize) {
         // Adds n indeces to the memory.
         // If we were clever, we would not do anything if the
         // memory can already compensate n elements.
+        if n == 0 {
+            return;
+        }
         let old_size = self.memory.len();
         let new_size = old_size + n;
         let old_head = self.free_head;
@@ -124,9 +287,109 @@ impl Pile {
             }
         }));
         self.free_head = Some(old_size);
+        self.counters.grow();
+        debug_validate!(self);
+    }
+
+    /// Memory statistics. The capacity includes the nursery, but only the
+    /// free entries in memory count as free (nursery holes are not reused).
+    pub fn stats(&self) -> Stats {
+        let free = self.memory.iter().map(|e| matches!(e, Entry::Free { .. }));
+        let old = self.allocated - self.young();
+        let mut stats = Stats {
+            live:               self.allocated,
+            capacity:           self.memory.len() + self.nursery_size,
+            free:               self.memory.len() - old
+                                    + self.nursery_size.saturating_sub(self.nursery.len()),
+            largest_free_run:   stats::largest_run(free),
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check the free memory and handle lists, and that every used handle
+    /// refers to its own live cell.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let free = invariant::walk("memory", self.free_head, self.memory.len(), |i| {
+            match self.memory[i] {
+                Entry::Free { next }  => Some(next),
+                Entry::Value { .. }   => None,
+            }
+        })?;
+
+        let unused = invariant::walk("handle", self.handle_head, self.handles.len(), |i| {
+            match self.handles[i] {
+                Handle::Unused { next } => Some(next),
+                _                       => None,
+            }
+        })?;
+
+        let mut seen = vec![false; self.memory.len()];
+        let mut seen_young = vec![false; self.nursery.len()];
+        let mut live = 0;
+        let mut live_young = 0;
+        let mut blocks = 0;
+        for (i, h) in self.handles.iter().enumerate() {
+            let (region, seen, address) = match *h {
+                Handle::Used { addr }  => { live += 1; (&self.memory, &mut seen, addr) },
+                Handle::Young { addr } => { live_young += 1; (&self.nursery, &mut seen_young, addr) },
+                Handle::Bytes          => match self.bytes.block(i) {
+                    Some(_) => { blocks += 1; continue },
+                    None    => return Err(Violation::Dangling { slot: i, address: 0 }),
+                },
+                Handle::Unused { .. }  => continue,
+            };
+
+            match region.get(address) {
+                Some(Entry::Value { .. }) => (),
+                _ => return Err(Violation::Dangling { slot: i, address }),
+            }
+            if seen[address] {
+                return Err(Violation::Shared { slot: i, address });
+            }
+            seen[address] = true;
+        }
+
+        let free = free.iter().filter(|&&f| f).count();
+        let unused = unused.iter().filter(|&&f| f).count();
+        let holes = self.nursery.len() - self.young();
+        invariant::count("memory", self.allocated - live_young, free, self.memory.len())?;
+        invariant::count("memory", live, free, self.memory.len())?;
+        invariant::count("nursery", live_young, holes, self.nursery.len())?;
+        invariant::count("handle", live + live_young + blocks, unused, self.handles.len())?;
+        if blocks != self.live_bytes() {
+            return Err(Violation::Count {
+                list: "bytes", live: blocks, free: 0, capacity: self.live_bytes(),
+            });
+        }
+        self.bytes.validate()
+    }
+
+    /* Number of live objects in the nursery */
+    fn young(&self) -> usize {
+        self.nursery.iter().filter(|e| matches!(e, Entry::Value { .. })).count()
     }
 
-    fn try_alloc(&mut self, t: u8) -> Result<Pointer, u8> {
+    fn try_alloc_young(&mut self, t: T) -> Result<Pointer, T> {
+        // same deal as try_alloc, but bump allocates in the nursery
+        if self.nursery.len() == self.nursery_size {
+            return Err(t);
+        }
+
+        let addr = self.nursery.len();
+        self.nursery.push(Entry::Value {
+            value:  t,
+            colour: Colour::White,
+        });
+        self.allocated += 1;
+
+        Ok(Pointer {
+            handle: self.get_handle(Handle::Young { addr }),
+        })
+    }
+
+    fn try_alloc(&mut self, t: T) -> Result<Pointer, T> {
         // note about return type:
         // we move t, so if we can't insert it we need to give it back :)
         match self.free_head {
@@ -137,60 +400,62 @@ impl Pile {
                     self.free_head = next;
                     self.allocated += 1;
                     self.memory[i] = Entry::Value {
-                        value: Rc::new(RefCell::new(t))
+                        value:  t,
+                        colour: Colour::White,
                     };
 
                     Ok(Pointer {
-                        handle: self.get_handle(i),
+                        handle: self.get_handle(Handle::Used { addr: i }),
                     })
                 }
             },
         }
     }
 
-    fn grow_and_alloc(&mut self, t: u8) -> Pointer {
-        let len = self.memory.len();
-        self.reserve(len); // double length each time, possibly tweak this
+    fn grow_and_alloc(&mut self, t: T) -> Pointer {
+        let len = self.memory.len() as f64 * (self.auto.growth() - 1.0);
+        self.reserve((len.ceil() as usize).max(1)); // adapted by the trigger
         self.try_alloc(t)
             .map_err(|_| ())
             .expect("inserting will always succeed after reserving additional space")
     }
 
-    fn get_handle(&mut self, address: usize) -> usize {
-        match self.try_get_handle(address) {
+    fn get_handle(&mut self, target: Handle) -> usize {
+        match self.try_get_handle(target) {
             Ok(h) => h,
             Err(u)  => self.grow_and_get_handle(u),
         }
     }
 
-    fn try_get_handle(&mut self, address: usize) -> Result<usize, usize> {
+    fn try_get_handle(&mut self, target: Handle) -> Result<usize, Handle> {
         // note about return type:
         // we move t, so if we can't insert it we need to give it back :)
         match self.handle_head {
-            None => Err(address),
+            None => Err(target),
             Some(i) => match self.handles[i] {
-                Handle::Used { .. } => panic!("corrupt handle list"),
                 Handle::Unused { next } => {
                     self.handle_head = next;
-                    self.handles[i] = Handle::Used {
-                        addr: address
-                    };
+                    self.handles[i] = target;
 
                     Ok(i)
-                }
+                },
+                _ => panic!("corrupt handle list"),
             },
         }
     }
 
-    fn grow_and_get_handle(&mut self, address: usize) -> usize {
-        let len = self.handles.len();
+    fn grow_and_get_handle(&mut self, target: Handle) -> usize {
+        let len = self.handles.len().max(1);
         self.reserve_handles(len); // double length each time, possibly tweak this
-        self.try_get_handle(address)
+        self.try_get_handle(target)
             .map_err(|_| ())
             .expect("inserting will always succeed after reserving additional space")
     }
 
     fn reserve_handles(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
         let old_size = self.handles.len();
         let new_size = old_size + n;
         let old_head = self.handle_head;
@@ -207,5 +472,215 @@ impl Pile {
             }
         }));
         self.handle_head = Some(old_size);
+        self.generations.resize(new_size, 0);
+    }
+}
+
+impl<T: Trace> Pile<T> {
+    /// Borrow an object mutably. Dropping the borrow applies the write
+    /// barrier for the pointers it holds, so they may be changed freely.
+    pub fn get_mut(&mut self, p: Pointer) -> Option<PileRefMut<'_, T>> {
+        self.cell(p.handle)?;
+        Some(PileRefMut::new(self, p.handle))
+    }
+
+    /// Allocate an object. The object stays alive (through `collect`) for as
+    /// long as the returned root, or one of its clones, does. May collect
+    /// first, depending on the trigger.
+    pub fn alloc(&mut self, t: T) -> Root<T> {
+        self.auto_collect();
+
+        let ptr = match self.try_alloc_young(t) {
+            Ok(ptr) => ptr,
+            Err(t)  => match self.try_alloc(t) {
+                Ok(ptr) => ptr,
+                Err(u)  => self.grow_and_alloc(u), // u is t, back from try_alloc
+            },
+        };
+        let colour = self.birth_colour(ptr.handle);
+        self.set_colour(ptr.handle, colour);
+
+        /* The pointers it starts with are stored like any other: a black
+         * object may not point to white ones, and one that did not fit in
+         * the nursery may point into it */
+        if self.phase == Phase::Mark || !self.nursery.is_empty() {
+            let mut stored = Vec::new();
+            if let Some(Entry::Value { value, .. }) = self.cell(ptr.handle) {
+                value.trace(&mut |p| stored.push(p));
+            }
+            for p in stored {
+                self.write_barrier(ptr, p);
+            }
+        }
+        self.counters.insert(self.allocated);
+        debug_validate!(self);
+        Root::new(ptr, &self.roots)
+    }
+}
+
+impl Trace for Pointer {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        tracer(*self)
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        tracer(self)
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        if let Some(ref t) = *self {
+            t.trace(tracer);
+        }
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        if let Some(ref mut t) = *self {
+            t.trace_mut(tracer);
+        }
+    }
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        if let Some(ref mut t) = *self {
+            t.trace_weak_mut(tracer);
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        for t in self {
+            t.trace(tracer);
+        }
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        for t in self {
+            t.trace_mut(tracer);
+        }
+    }
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        for t in self {
+            t.trace_weak_mut(tracer);
+        }
+    }
+}
+
+impl<T: Trace + ?Sized> Trace for Box<T> {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        (**self).trace(tracer)
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        (**self).trace_mut(tracer)
+    }
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        (**self).trace_weak_mut(tracer)
+    }
+}
+
+/* For objects that are mutated through shared borrows */
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        self.borrow().trace(tracer)
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        self.get_mut().trace_mut(tracer)
+    }
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        self.get_mut().trace_weak_mut(tracer)
+    }
+}
+
+impl<A: Trace, B: Trace> Trace for (A, B) {
+    fn trace(&self, tracer: &mut dyn FnMut(Pointer)) {
+        self.0.trace(tracer);
+        self.1.trace(tracer);
+    }
+
+    fn trace_mut(&mut self, tracer: &mut dyn FnMut(&mut Pointer)) {
+        self.0.trace_mut(tracer);
+        self.1.trace_mut(tracer);
+    }
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        self.0.trace_weak_mut(tracer);
+        self.1.trace_weak_mut(tracer);
+    }
+}
+
+/* Types that can never hold a pointer */
+macro_rules! impl_trace_leaf {
+    ($($t:ty),*) => {
+        $(
+            impl Trace for $t {
+                fn trace(&self, _: &mut dyn FnMut(Pointer)) {}
+                fn trace_mut(&mut self, _: &mut dyn FnMut(&mut Pointer)) {}
+            }
+        )*
+    }
+}
+
+impl_trace_leaf!(
+    (), bool, char, String,
+    u8, u16, u32, u64, usize,
+    i8, i16, i32, i64, isize,
+    f32, f64
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_after_free() {
+        let mut pile: Pile<u32> = Pile::with_capacity(0);
+        let a = pile.alloc(1);
+        let b = pile.alloc(2);
+        assert_eq!(*pile.get(a.pointer()).unwrap(), 1);
+        pile.free(a.pointer());
+        pile.free(a.pointer());
+        assert!(pile.get(a.pointer()).is_none());
+        assert_eq!(*pile.get(b.pointer()).unwrap(), 2);
+        assert_eq!(pile.stats().live, 1);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn valid_after_compact() {
+        let mut pile: Pile<u32> = Pile::new();
+        let roots: Vec<_> = (0..8).map(|i| pile.alloc(i)).collect();
+        for r in roots.iter().step_by(2) {
+            pile.free(r.pointer());
+        }
+        pile.compact();
+        for (i, r) in roots.iter().enumerate().skip(1).step_by(2) {
+            assert_eq!(*pile.get(r.pointer()).unwrap(), i as u32);
+        }
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_finds_corruption() {
+        let mut pile: Pile<u32> = Pile::new();
+        let a = pile.alloc(1);
+        let b = pile.alloc(2);
+        pile.handles[b.pointer().handle] = pile.handles[a.pointer().handle];
+        assert!(matches!(pile.validate(), Err(Violation::Shared { .. })));
+
+        let mut pile: Pile<u32> = Pile::new();
+        let a = pile.alloc(1);
+        pile.handles[a.pointer().handle] = Handle::Used { addr: pile.memory.len() };
+        assert!(matches!(pile.validate(), Err(Violation::Dangling { .. })));
+
+        let mut pile: Pile<u32> = Pile::with_capacity(4);
+        let last = pile.memory.len() - 1;
+        pile.memory[last] = Entry::Free { next: pile.free_head };
+        assert!(matches!(pile.validate(), Err(Violation::Cycle { .. })));
     }
 }
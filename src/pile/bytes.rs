@@ -0,0 +1,323 @@
+/*
+ * Variable-sized byte blocks.
+ *
+ * Next to its objects, a pile can hold raw byte blocks (for strings and
+ * buffers), carved out of one byte buffer. The buffer is made of aligned
+ * chunks, so a block is aligned in memory when its offset is. A free block
+ * is found with the selected `Fit` policy, and split: the alignment gap in
+ * front of the block and the rest behind it stay free. Freeing a block
+ * coalesces it with its free neighbours, so no two free blocks ever touch.
+ *
+ * Blocks are reached through handles like objects, so growing the buffer
+ * may move it. They hold no pointers; the collector frees the blocks that
+ * no object points to, like any other object.
+ */
+
+use std::alloc::Layout;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::slice;
+
+use invariant::{self, Violation};
+
+use super::{Colour, Handle, Pile, Pointer, Root, Trace};
+
+/* Every block offset and size is a multiple of this */
+const GRAIN: usize = 8;
+
+/* The buffer grows in chunks, which are aligned to their size */
+const CHUNK: usize = 4096;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct Chunk([u8; CHUNK]);
+
+/// How `Pile::alloc_bytes` picks the free block to carve from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// The first block, in address order, that fits.
+    First,
+    /// The first block that fits after the last allocation, wrapping
+    /// around, which spreads allocations over the buffer.
+    Next,
+    /// The smallest block that fits.
+    Best,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub offset: usize,
+    pub size:   usize,  /* carved out, a multiple of GRAIN */
+    pub len:    usize,  /* asked for */
+    pub align:  usize,  /* asked for */
+    pub colour: Colour,
+}
+
+/* The byte region of a pile */
+pub struct Bytes {
+    chunks: Vec<Chunk>,
+    free:   BTreeMap<usize, usize>,     /* offset -> size, in address order */
+    used:   HashMap<usize, Block>,      /* handle -> block */
+    fit:    Fit,
+    rover:  usize,                      /* where next-fit starts looking */
+}
+
+impl Bytes {
+    pub fn new() -> Bytes {
+        Bytes {
+            chunks: Vec::new(),
+            free:   BTreeMap::new(),
+            used:   HashMap::new(),
+            fit:    Fit::First,
+            rover:  0,
+        }
+    }
+
+    pub fn block(&self, handle: usize) -> Option<&Block> {
+        self.used.get(&handle)
+    }
+
+    pub fn block_mut(&mut self, handle: usize) -> Option<&mut Block> {
+        self.used.get_mut(&handle)
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.len() * CHUNK
+    }
+
+    /* Find room for the block of `handle`, growing if there is none */
+    pub fn insert(&mut self, handle: usize, layout: Layout, colour: Colour) {
+        assert!(layout.align() <= CHUNK, "alignment {} is larger than a chunk", layout.align());
+        let size = align_up(layout.size().max(1), GRAIN);
+        let align = layout.align().max(GRAIN);
+
+        let offset = match self.carve(size, align) {
+            Some(offset) => offset,
+            None         => {
+                self.grow(size, align);
+                self.carve(size, align).expect("a block always fits after growing")
+            },
+        };
+        let block = Block { offset, size, len: layout.size(), align: layout.align(), colour };
+        self.used.insert(handle, block);
+    }
+
+    /* Carve an aligned block out of a free one; the offset on success */
+    fn carve(&mut self, size: usize, align: usize) -> Option<usize> {
+        let fits = |(&offset, &free): (&usize, &usize)| {
+            let start = align_up(offset, align);
+            if start + size <= offset + free { Some((offset, free)) } else { None }
+        };
+
+        let (offset, free) = match self.fit {
+            Fit::First => self.free.iter().filter_map(fits).next()?,
+            Fit::Next  => self.free.range(self.rover..)
+                .chain(self.free.range(..self.rover))
+                .filter_map(fits)
+                .next()?,
+            Fit::Best  => self.free.iter()
+                .filter_map(fits)
+                .min_by_key(|&(offset, free)| (free, offset))?,
+        };
+
+        let start = align_up(offset, align);
+        let end = start + size;
+        self.free.remove(&offset);
+        if start > offset {
+            self.free.insert(offset, start - offset);
+        }
+        if end < offset + free {
+            self.free.insert(end, offset + free - end);
+        }
+        self.rover = end;
+        Some(start)
+    }
+
+    /* Add enough chunks to fit the block anywhere in the new ones */
+    fn grow(&mut self, size: usize, align: usize) {
+        let needed = (size + align - 1).div_ceil(CHUNK);
+        let n = needed.max(self.chunks.len()); // at least double
+        let old = self.capacity();
+        self.chunks.resize(self.chunks.len() + n, Chunk([0; CHUNK]));
+        self.release(old, n * CHUNK);
+    }
+
+    /* Give a range back, merging it with the free blocks around it */
+    fn release(&mut self, offset: usize, size: usize) {
+        let mut offset = offset;
+        let mut size = size;
+
+        let before = self.free.range(..offset).next_back().map(|(&o, &s)| (o, s));
+        if let Some((o, s)) = before {
+            if o + s == offset {
+                self.free.remove(&o);
+                offset = o;
+                size += s;
+            }
+        }
+        if let Some(s) = self.free.remove(&(offset + size)) {
+            size += s;
+        }
+        self.free.insert(offset, size);
+    }
+
+    fn buffer(&self) -> &[u8] {
+        /* The chunks are plain bytes, laid out back to back */
+        unsafe { slice::from_raw_parts(self.chunks.as_ptr() as *const u8, self.capacity()) }
+    }
+
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        let len = self.capacity();
+        unsafe { slice::from_raw_parts_mut(self.chunks.as_mut_ptr() as *mut u8, len) }
+    }
+
+    /* The blocks and the free list tile the buffer, with no two free
+     * blocks touching */
+    pub fn validate(&self) -> Result<(), Violation> {
+        let mut ranges: Vec<(usize, usize, bool)> = self.free.iter()
+            .map(|(&o, &s)| (o, s, true))
+            .chain(self.used.values().map(|b| (b.offset, b.size, false)))
+            .collect();
+        ranges.sort();
+
+        let mut end = 0;
+        let mut free_before = false;
+        for (offset, size, free) in ranges {
+            if offset < end {
+                return Err(Violation::Overlap { list: "bytes", offset });
+            }
+            if free && free_before && offset == end {
+                return Err(Violation::Uncoalesced { list: "bytes", offset });
+            }
+            free_before = free;
+            end = offset + size;
+        }
+
+        let used: usize = self.used.values().map(|b| b.size).sum();
+        let free: usize = self.free.values().sum();
+        invariant::count("bytes", used, free, self.capacity())
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bytes")
+            .field("capacity", &self.capacity())
+            .field("free", &self.free)
+            .field("used", &self.used)
+            .field("fit", &self.fit)
+            .finish()
+    }
+}
+
+impl<T: Trace> Pile<T> {
+    /// Allocate a block of bytes. Fresh memory is zeroed, but a reused
+    /// block keeps whatever was written to it before. Like objects, a block
+    /// stays alive for as long as the returned root does, or an object
+    /// points to it. May collect first, depending on the trigger. Panics if
+    /// the alignment is larger than 4096.
+    pub fn alloc_bytes(&mut self, layout: Layout) -> Root<T> {
+        assert!(layout.align() <= CHUNK, "alignment {} is larger than a chunk", layout.align());
+        self.auto_collect();
+
+        let handle = self.get_handle(Handle::Bytes);
+        let colour = self.birth_colour(handle);
+        self.bytes.insert(handle, layout, colour);
+        debug_validate!(self);
+        Root::new(Pointer { handle }, &self.roots)
+    }
+}
+
+impl<T> Pile<T> {
+    /// Choose how `alloc_bytes` finds room for a block.
+    pub fn set_fit(&mut self, fit: Fit) {
+        self.bytes.fit = fit;
+    }
+
+    /// The bytes of a block. Returns `None` if `p` does not point to a
+    /// block.
+    pub fn bytes(&self, p: Pointer) -> Option<&[u8]> {
+        let b = *self.bytes.block(p.handle)?;
+        Some(&self.bytes.buffer()[b.offset..b.offset + b.len])
+    }
+
+    pub fn bytes_mut(&mut self, p: Pointer) -> Option<&mut [u8]> {
+        let b = *self.bytes.block(p.handle)?;
+        Some(&mut self.bytes.buffer_mut()[b.offset..b.offset + b.len])
+    }
+
+    /* Called by `free` once the handle is given back */
+    pub(super) fn free_bytes(&mut self, handle: usize) {
+        let b = self.bytes.used.remove(&handle).expect("corrupt handle table");
+        self.bytes.release(b.offset, b.size);
+    }
+
+    pub(super) fn live_bytes(&self) -> usize {
+        self.bytes.used.len()
+    }
+}
+
+fn align_up(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Node = Option<Pointer>;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn blocks_are_rooted_and_collected() {
+        let mut pile: Pile<Node> = Pile::new();
+        let rooted = pile.alloc_bytes(layout(10));
+        let held = pile.alloc_bytes(layout(20)).pointer();
+        let holder = pile.alloc(Some(held));
+        let lost = pile.alloc_bytes(layout(30)).pointer();
+
+        pile.collect();
+        assert_eq!(pile.bytes(rooted.pointer()).map(|b| b.len()), Some(10));
+        assert_eq!(pile.bytes(held).map(|b| b.len()), Some(20));
+        assert!(pile.bytes(lost).is_none());
+
+        drop(holder);
+        drop(rooted);
+        pile.collect();
+        assert!(pile.bytes(held).is_none());
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn freeing_coalesces() {
+        for &fit in &[Fit::First, Fit::Next, Fit::Best] {
+            let mut pile: Pile<Node> = Pile::new();
+            pile.set_fit(fit);
+            let blocks: Vec<_> = (1..10).map(|n| pile.alloc_bytes(layout(n * 8))).collect();
+            for (i, b) in blocks.iter().enumerate() {
+                if i % 2 == 0 {
+                    pile.free(b.pointer());
+                }
+            }
+            assert_eq!(pile.validate(), Ok(()));
+            for b in &blocks {
+                pile.free(b.pointer());
+            }
+            assert_eq!(pile.validate(), Ok(()));
+            assert_eq!(pile.bytes.free.len(), 1);
+        }
+    }
+
+    #[test]
+    fn validate_finds_overlap() {
+        let mut pile: Pile<Node> = Pile::new();
+        let a = pile.alloc_bytes(layout(16));
+        let b = pile.alloc_bytes(layout(16));
+        let offset = pile.bytes.block(a.pointer().handle).unwrap().offset;
+        pile.bytes.block_mut(b.pointer().handle).unwrap().offset = offset + 8;
+        assert!(pile.bytes.validate().is_err());
+    }
+}
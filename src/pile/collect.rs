@@ -0,0 +1,280 @@
+/*
+ * Incremental tri-colour mark and sweep.
+ *
+ * A collection cycle starts by shading the roots grey. Marking then takes
+ * grey objects one at a time, shades everything they point to grey, and
+ * blackens them. When no grey objects are left, every white object is
+ * unreachable, and sweeping frees them and whitens the survivors.
+ *
+ * Both phases can be split into steps with `collect_step`, so the mutator
+ * runs in between. That is only sound while no black object points to a
+ * white one, which the mutator upholds by calling `write_barrier` whenever
+ * it stores a pointer in an object. Objects allocated during a cycle are
+ * allocated black, so they survive it, and the pointers they start with go
+ * through the write barrier.
+ *
+ * Once marking is done, the values of ephemeron tables whose keys were
+ * marked are shaded, and then dead objects with finalizers; marking resumes
+ * from them until nothing new is shaded. See `ephemeron` and `finalize`.
+ */
+
+use super::{Colour, Entry, Handle, Pile, Pointer, Trace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Mark,
+    Sweep { cursor: usize, freed: usize },
+}
+
+impl<T> Pile<T> {
+    /// Must be called whenever a pointer to `stored` is written into the
+    /// object `holder`. Keeps an ongoing cycle sound, and records pointers
+    /// from old objects into the nursery for `collect_minor`.
+    pub fn write_barrier(&mut self, holder: Pointer, stored: Pointer) {
+        self.remember(holder, stored);
+        if self.phase == Phase::Mark && self.colour(holder.handle) == Some(Colour::Black) {
+            self.shade(stored.handle);
+        }
+    }
+
+    /* The colour a freshly allocated object at `handle` starts out with */
+    pub(super) fn birth_colour(&self, handle: usize) -> Colour {
+        match self.phase {
+            Phase::Idle                             => Colour::White,
+            Phase::Mark                             => Colour::Black,
+            Phase::Sweep { cursor, .. } if handle < cursor => Colour::White,
+            Phase::Sweep { .. }                     => Colour::Black,
+        }
+    }
+
+    /* Make a white object grey, and queue it for marking */
+    pub(super) fn shade(&mut self, handle: usize) {
+        if self.colour(handle) == Some(Colour::White) {
+            self.set_colour(handle, Colour::Grey);
+            self.grey.push(handle);
+        }
+    }
+
+    pub(super) fn colour(&self, handle: usize) -> Option<Colour> {
+        if let Some(Handle::Bytes) = self.handles.get(handle) {
+            return self.bytes.block(handle).map(|b| b.colour);
+        }
+        match self.cell(handle) {
+            Some(&Entry::Value { colour, .. }) => Some(colour),
+            Some(&Entry::Free { .. })          => panic!("corrupt handle table"),
+            None                               => None,
+        }
+    }
+
+    pub(super) fn set_colour(&mut self, handle: usize, c: Colour) {
+        if let Some(b) = self.bytes.block_mut(handle) {
+            b.colour = c;
+            return;
+        }
+        if let Some(&mut Entry::Value { ref mut colour, .. }) = self.cell_mut(handle) {
+            *colour = c;
+        }
+    }
+}
+
+impl<T: Trace> Pile<T> {
+    /// Free every object that is not reachable from a root. Finishes the
+    /// cycle in progress, if any, before running a full one. Returns the
+    /// number of objects freed.
+    pub fn collect(&mut self) -> usize {
+        let mut freed = 0;
+        if self.phase != Phase::Idle {
+            freed += self.finish_cycle();
+        }
+        freed + self.finish_cycle()
+    }
+
+    /// Do at most `budget` units of collection work, where a unit is
+    /// marking or sweeping one object. Starts a new cycle if none is in
+    /// progress. Returns the number of objects freed if the cycle finished.
+    pub fn collect_step(&mut self, budget: usize) -> Option<usize> {
+        let mut budget = budget;
+
+        if self.phase == Phase::Idle {
+            let roots = self.roots.borrow().handles();
+            for handle in roots {
+                self.shade(handle);
+            }
+            self.phase = Phase::Mark;
+        }
+
+        while budget > 0 {
+            budget -= 1;
+            match self.phase {
+                Phase::Idle => unreachable!(),
+                Phase::Mark => self.mark_one(),
+                Phase::Sweep { .. } => if let Some(freed) = self.sweep_one() {
+                    return Some(freed);
+                },
+            }
+        }
+
+        None
+    }
+
+    fn finish_cycle(&mut self) -> usize {
+        loop {
+            if let Some(freed) = self.collect_step(usize::MAX) {
+                return freed;
+            }
+        }
+    }
+
+    fn mark_one(&mut self) {
+        let handle = match self.grey.pop() {
+            Some(handle) => handle,
+            None => {
+                /* Catch roots added behind our back before finishing */
+                let roots = self.roots.borrow().handles();
+                for handle in roots {
+                    self.shade(handle);
+                }
+                if self.grey.is_empty() {
+                    self.trace_ephemerons();
+                }
+                if self.grey.is_empty() {
+                    self.doom_white();
+                }
+                if self.grey.is_empty() {
+                    self.phase = Phase::Sweep { cursor: 0, freed: 0 };
+                }
+                return;
+            },
+        };
+
+        /* Byte blocks hold no pointers */
+        if self.bytes.block(handle).is_some() {
+            self.set_colour(handle, Colour::Black);
+            return;
+        }
+
+        let mut children = Vec::new();
+        if let Some(&mut Entry::Value { ref value, ref mut colour }) = self.cell_mut(handle) {
+            if *colour != Colour::Grey {
+                return;
+            }
+            *colour = Colour::Black;
+            value.trace(&mut |p| children.push(p.handle));
+        }
+
+        for child in children {
+            self.shade(child);
+        }
+    }
+
+    fn sweep_one(&mut self) -> Option<usize> {
+        let (cursor, mut freed) = match self.phase {
+            Phase::Sweep { cursor, freed } => (cursor, freed),
+            _                              => unreachable!(),
+        };
+
+        if cursor == self.handles.len() {
+            self.phase = Phase::Idle;
+            self.prune_ephemerons();
+            self.finalize_pending();
+            return Some(freed);
+        }
+
+        match self.colour(cursor) {
+            Some(Colour::White) => {
+                self.free(Pointer { handle: cursor });
+                freed += 1;
+            },
+            Some(_) => self.set_colour(cursor, Colour::White),
+            None    => (),
+        }
+
+        self.phase = Phase::Sweep { cursor: cursor + 1, freed };
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Root;
+    use super::*;
+
+    type Node = (u32, Option<Pointer>);
+
+    fn link(pile: &mut Pile<Node>, from: &Root<Node>, to: Pointer) {
+        pile.get_mut(from.pointer()).unwrap().1 = Some(to);
+    }
+
+    #[test]
+    fn frees_unreachable() {
+        let mut pile: Pile<Node> = Pile::new();
+        let root = pile.alloc((0, None));
+        let child = pile.alloc((1, None)).pointer();
+        link(&mut pile, &root, child);
+        let lost = pile.alloc((2, None)).pointer();
+
+        assert_eq!(pile.collect(), 1);
+        assert!(pile.get(child).is_some());
+        assert!(pile.get(lost).is_none());
+
+        drop(root);
+        assert_eq!(pile.collect(), 2);
+        assert_eq!(pile.stats().live, 0);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn frees_cycles() {
+        let mut pile: Pile<Node> = Pile::new();
+        let a = pile.alloc((0, None));
+        let b = pile.alloc((1, Some(a.pointer())));
+        link(&mut pile, &a, b.pointer());
+        drop((a, b));
+        assert_eq!(pile.collect(), 2);
+    }
+
+    #[test]
+    fn incremental_with_write_barrier() {
+        let mut pile: Pile<Node> = Pile::new();
+        let root = pile.alloc((0, None));
+        let loose = pile.alloc((1, None)).pointer();
+
+        /* The root is marked black, then made to point at a white object */
+        assert_eq!(pile.collect_step(1), None);
+        link(&mut pile, &root, loose);
+        let young = pile.alloc((2, None)).pointer();
+
+        let freed = loop {
+            if let Some(freed) = pile.collect_step(1) {
+                break freed;
+            }
+        };
+        assert_eq!(freed, 0);
+        assert!(pile.get(loose).is_some());
+
+        /* Allocated during the cycle, so it only dies in the next one */
+        assert!(pile.get(young).is_some());
+        assert_eq!(pile.collect(), 1);
+        assert!(pile.get(young).is_none());
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn allocated_during_mark() {
+        let mut pile: Pile<Node> = Pile::new();
+        let holder = pile.alloc((0, None));
+        let x = pile.alloc((1, None)).pointer();
+        link(&mut pile, &holder, x);
+
+        /* The holder is grey; a black newcomer takes over its pointer */
+        assert_eq!(pile.collect_step(0), None);
+        let fresh = pile.alloc((2, Some(x)));
+        pile.get_mut(holder.pointer()).unwrap().1 = None;
+
+        assert_eq!(pile.collect(), 0);
+        assert_eq!(pile.get(fresh.pointer()).unwrap().1, Some(x));
+        assert_eq!(pile.get(x).unwrap().0, 1);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+}
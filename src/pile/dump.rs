@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use dot::{self, Graph, Mode};
+
+use super::{Entry, Pile, Pointer, Trace};
+
+impl<T: Trace> Pile<T> {
+    /// Write the pile as a Graphviz graph: one node per live object,
+    /// labelled with its handle and `label`, and one edge per pointer.
+    pub fn to_dot<F>(&self, w: &mut impl Write, mode: Mode, label: F) -> io::Result<()>
+        where F: Fn(&T) -> String
+    {
+        let len = self.handles.len();
+        let mut edges = vec![Vec::new(); len];
+        for (handle, edges) in edges.iter_mut().enumerate() {
+            if let Some(Entry::Value { value, .. }) = self.cell(handle) {
+                value.trace(&mut |p| edges.push(p.handle));
+            }
+        }
+
+        let roots = self.roots.borrow().handles();
+        let reachable = match mode {
+            Mode::Roots       => vec![true; len],
+            Mode::Unreachable => dot::reachable(len, &roots, &edges),
+        };
+        let roots: HashSet<usize> = roots.into_iter().collect();
+
+        let mut graph = Graph::begin(w, "pile", "h")?;
+        let mut dangling = HashSet::new();
+        for (handle, targets) in edges.iter().enumerate() {
+            let text = match self.cell(handle) {
+                Some(Entry::Value { value, .. }) => format!("#{}\n{}", handle, label(value)),
+                _ => match self.bytes(Pointer { handle }) {
+                    Some(bytes) => format!("#{}\n{} bytes", handle, bytes.len()),
+                    None        => continue,
+                },
+            };
+            graph.node(handle, &text, roots.contains(&handle), !reachable[handle])?;
+
+            for &target in targets {
+                if self.colour(target).is_none() && dangling.insert(target) {
+                    graph.dangling(target, &format!("#{} (freed)", target))?;
+                }
+                graph.edge(handle, target)?;
+            }
+        }
+        graph.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    type Node = (u32, Option<Pointer>);
+
+    fn dump(pile: &Pile<Node>, mode: Mode) -> String {
+        let mut out = Vec::new();
+        pile.to_dot(&mut out, mode, |n| format!("value {}", n.0)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /* The handles of the nodes whose line contains `style` */
+    fn styled(dot: &str, style: &str) -> BTreeSet<usize> {
+        dot.lines()
+            .filter(|l| l.contains("[label=") && l.contains(style))
+            .map(|l| l.trim_start()[1..].split(' ').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nodes_and_edges() {
+        let mut pile: Pile<Node> = Pile::new();
+        let leaf = pile.alloc((1, None)).pointer();
+        let root = pile.alloc((2, Some(leaf)));
+        let (l, r) = (leaf.handle, root.pointer().handle);
+
+        let dot = dump(&pile, Mode::Roots);
+        assert!(dot.contains(&format!("h{} [label=\"#{}\\nvalue 1\"];", l, l)));
+        assert!(dot.contains(&format!("h{} -> h{};", r, l)));
+        assert_eq!(dot.matches("->").count(), 1);
+        assert_eq!(styled(&dot, "style=bold"), [r].iter().cloned().collect());
+        assert!(styled(&dot, "fillcolor").is_empty());
+    }
+
+    #[test]
+    fn unreachable_is_what_collect_frees() {
+        let mut pile: Pile<Node> = Pile::new();
+        let kept = pile.alloc((0, None)).pointer();
+        let _root = pile.alloc((1, Some(kept)));
+        let a = pile.alloc((2, None)).pointer();
+        let b = pile.alloc((3, Some(a))).pointer();
+        pile.get_mut(a).unwrap().1 = Some(b);
+        pile.alloc((4, Some(kept)));
+
+        let marked = styled(&dump(&pile, Mode::Unreachable), "fillcolor");
+        let before: BTreeSet<usize> = (0..pile.handles.len())
+            .filter(|&h| pile.get(Pointer { handle: h }).is_some())
+            .collect();
+        assert_eq!(marked.len(), 3);
+        assert_eq!(pile.collect(), 3);
+        let after: BTreeSet<usize> = (0..pile.handles.len())
+            .filter(|&h| pile.get(Pointer { handle: h }).is_some())
+            .collect();
+        assert_eq!(before.difference(&after).cloned().collect::<BTreeSet<_>>(), marked);
+    }
+
+    #[test]
+    fn freed_targets() {
+        let mut pile: Pile<Node> = Pile::new();
+        let gone = pile.alloc((1, None)).pointer();
+        let _root = pile.alloc((2, Some(gone)));
+        pile.free(gone);
+
+        let dot = dump(&pile, Mode::Roots);
+        assert!(dot.contains(&format!("h{} [label=\"#{} (freed)\", style=dashed", gone.handle, gone.handle)));
+    }
+}
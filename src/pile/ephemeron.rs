@@ -0,0 +1,250 @@
+/*
+ * Ephemeron tables.
+ *
+ * An ephemeron map associates values with pile objects without keeping the
+ * objects alive: a value is only traced once its key is known to be
+ * reachable, so a value pointing back at its own key does not keep the
+ * pair alive. Marking can make more keys reachable by tracing values, so
+ * whenever marking runs out of grey objects, the values of every marked key
+ * are traced again, until that shades nothing new.
+ *
+ * Keys are stored with their handle's generation, like weak pointers, and
+ * entries whose key was freed are dropped after the sweep. The pile only
+ * holds its tables weakly, so dropping a map unregisters it.
+ *
+ * A table that its user borrows mutably during a collection can not be
+ * read, and its values could point anywhere; the collector then keeps every
+ * object alive, rather than guess.
+ */
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use super::{Colour, Handle, Pile, Pointer, Trace};
+
+/* What the collector needs from a table, whatever its value type */
+pub trait Ephemerons {
+    /* Trace the values whose key (handle, generation) `live` accepts */
+    fn trace_values(&self, live: &dyn Fn(usize, u32) -> bool, tracer: &mut dyn FnMut(Pointer));
+
+    /* Drop the entries whose key `live` rejects */
+    fn retain(&mut self, live: &dyn Fn(usize, u32) -> bool);
+}
+
+pub type Table = Weak<RefCell<dyn Ephemerons>>;
+
+struct Entries<V> {
+    map: HashMap<usize, (u32, V)>,
+}
+
+impl<V: Trace> Ephemerons for Entries<V> {
+    fn trace_values(&self, live: &dyn Fn(usize, u32) -> bool, tracer: &mut dyn FnMut(Pointer)) {
+        for (&handle, &(generation, ref value)) in &self.map {
+            if live(handle, generation) {
+                value.trace(tracer);
+            }
+        }
+    }
+
+    fn retain(&mut self, live: &dyn Fn(usize, u32) -> bool) {
+        self.map.retain(|&handle, &mut (generation, _)| live(handle, generation));
+    }
+}
+
+/// A side table of values keyed by pile objects, whose entries go away
+/// when their key is collected.
+pub struct EphemeronMap<V> {
+    entries: Rc<RefCell<Entries<V>>>,
+}
+
+impl<V: Trace + 'static> EphemeronMap<V> {
+    /// An empty map for objects in `pile`.
+    pub fn new<T>(pile: &mut Pile<T>) -> EphemeronMap<V> {
+        let entries = Rc::new(RefCell::new(Entries { map: HashMap::new() }));
+        let table: Rc<RefCell<dyn Ephemerons>> = entries.clone();
+        pile.ephemerons.push(Rc::downgrade(&table));
+        EphemeronMap { entries }
+    }
+}
+
+impl<V> EphemeronMap<V> {
+    /// Associate `value` with the object at `key`, returning the value it
+    /// replaces. Gives `value` back if `key` does not point to an object.
+    pub fn insert<T>(&mut self, pile: &Pile<T>, key: Pointer, value: V) -> Result<Option<V>, V> {
+        let weak = match pile.downgrade(key) {
+            Some(weak) => weak,
+            None       => return Err(value),
+        };
+
+        let old = self.entries.borrow_mut().map.insert(key.handle, (weak.generation, value));
+        Ok(old.and_then(|(generation, v)| if generation == weak.generation { Some(v) } else { None }))
+    }
+
+    pub fn get<T>(&self, pile: &Pile<T>, key: Pointer) -> Option<Ref<'_, V>> {
+        let generation = pile.downgrade(key)?.generation;
+        Ref::filter_map(self.entries.borrow(), |e| match e.map.get(&key.handle) {
+            Some(&(g, ref v)) if g == generation => Some(v),
+            _                                    => None,
+        }).ok()
+    }
+
+    pub fn get_mut<T>(&mut self, pile: &Pile<T>, key: Pointer) -> Option<RefMut<'_, V>> {
+        let generation = pile.downgrade(key)?.generation;
+        RefMut::filter_map(self.entries.borrow_mut(), |e| match e.map.get_mut(&key.handle) {
+            Some(&mut (g, ref mut v)) if g == generation => Some(v),
+            _                                            => None,
+        }).ok()
+    }
+
+    pub fn remove<T>(&mut self, pile: &Pile<T>, key: Pointer) -> Option<V> {
+        let generation = pile.downgrade(key)?.generation;
+        let mut entries = self.entries.borrow_mut();
+        match entries.map.get(&key.handle) {
+            Some(&(g, _)) if g == generation => entries.map.remove(&key.handle).map(|(_, v)| v),
+            _                                => None,
+        }
+    }
+
+    /// Number of entries, including those whose key died since the last
+    /// collection.
+    pub fn len(&self) -> usize {
+        self.entries.borrow().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V> fmt::Debug for EphemeronMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EphemeronMap({} entries)", self.len())
+    }
+}
+
+impl<T> Pile<T> {
+    /* The tables that are still around, forgetting dropped ones */
+    fn tables(&mut self) -> Vec<Rc<RefCell<dyn Ephemerons>>> {
+        self.ephemerons.retain(|t| t.strong_count() > 0);
+        self.ephemerons.iter().filter_map(|t| t.upgrade()).collect()
+    }
+
+    fn key_alive(&self, handle: usize, generation: u32) -> bool {
+        self.generations.get(handle) == Some(&generation) && self.cell(handle).is_some()
+    }
+
+    /* Shade the values of the marked keys; marking goes on if that shaded
+     * anything */
+    pub(super) fn trace_ephemerons(&mut self) {
+        let mut values = Vec::new();
+        let mut busy = false;
+        for table in self.tables() {
+            match table.try_borrow() {
+                Ok(table) => table.trace_values(
+                    &|h, g| self.key_alive(h, g) && self.colour(h) != Some(Colour::White),
+                    &mut |p| values.push(p.handle),
+                ),
+                Err(_)    => busy = true,
+            }
+        }
+        if busy {
+            values.extend(0..self.handles.len());
+        }
+        for handle in values {
+            self.shade(handle);
+        }
+    }
+
+    /* Values of the young objects marked in `reachable`, and of all the old
+     * ones, for a minor collection */
+    pub(super) fn young_ephemeron_values(&mut self, reachable: &[bool]) -> Vec<usize> {
+        let mut values = Vec::new();
+        let mut busy = false;
+        for table in self.tables() {
+            match table.try_borrow() {
+                Ok(table) => table.trace_values(
+                    &|h, g| self.key_alive(h, g) && match self.handles[h] {
+                        Handle::Young { addr } => reachable[addr],
+                        _                      => true,
+                    },
+                    &mut |p| values.push(p.handle),
+                ),
+                Err(_)    => busy = true,
+            }
+        }
+        if busy {
+            values.extend(0..self.handles.len());
+        }
+        values
+    }
+
+    /* Drop the entries whose key is gone. A table that is borrowed right
+     * now keeps them until the next collection. */
+    pub(super) fn prune_ephemerons(&mut self) {
+        for table in self.tables() {
+            if let Ok(mut table) = table.try_borrow_mut() {
+                table.retain(&|h, g| self.key_alive(h, g));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Node = (u32, Option<Pointer>);
+
+    #[test]
+    fn value_lives_as_long_as_its_key() {
+        let mut pile: Pile<Node> = Pile::new();
+        let key = pile.alloc((0, None));
+        let target = pile.alloc((1, None)).pointer();
+        let mut map = EphemeronMap::new(&mut pile);
+        map.insert(&pile, key.pointer(), Some(target)).unwrap();
+
+        pile.collect();
+        assert!(pile.get(target).is_some());
+        assert_eq!(map.len(), 1);
+
+        drop(key);
+        pile.collect();
+        assert!(pile.get(target).is_none());
+        assert_eq!(map.len(), 0);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn value_pointing_at_its_key() {
+        let mut pile: Pile<Node> = Pile::new();
+        let key = pile.alloc((0, None)).pointer();
+        let mut map = EphemeronMap::new(&mut pile);
+        map.insert(&pile, key, Some(key)).unwrap();
+
+        pile.collect();
+        assert!(pile.get(key).is_none());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn borrowed_table_keeps_everything() {
+        let mut pile: Pile<Node> = Pile::new();
+        let key = pile.alloc((0, None));
+        let target = pile.alloc((1, None)).pointer();
+        let mut map = EphemeronMap::new(&mut pile);
+        map.insert(&pile, key.pointer(), None).unwrap();
+
+        let mut value = map.get_mut(&pile, key.pointer()).unwrap();
+        *value = Some(target);
+        pile.collect_minor();
+        pile.collect();
+        drop(value);
+
+        assert!(pile.get(target).is_some());
+        pile.collect();
+        assert!(pile.get(target).is_some());
+        assert_eq!(pile.validate(), Ok(()));
+    }
+}
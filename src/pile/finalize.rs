@@ -0,0 +1,175 @@
+/*
+ * Finalizers.
+ *
+ * An object can carry a finalizer, which runs once after a collection has
+ * found the object unreachable. When marking runs out of grey objects, the
+ * finalizers of the white objects are taken out of the registry and their
+ * objects are shaded, so they (and everything they point to) survive the
+ * cycle. The finalizers run when the sweep is done, and get the pile, so a
+ * finalizer can resurrect its object by rooting it or storing a pointer to
+ * it somewhere reachable. An object that is not resurrected is freed by the
+ * next cycle, without running its finalizer again.
+ *
+ * Objects that die together are finalized together, in no particular
+ * order, so a finalizer must not expect the objects its object points to
+ * to still be usable once their own finalizers have run.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use super::{Colour, Handle, Pile, Pointer, Root};
+
+type Finalizer<T> = Box<dyn FnOnce(Pointer, &mut Pile<T>)>;
+
+/* The finalizers of live objects, and those waiting for the sweep to end */
+pub struct Finalizers<T> {
+    registered: HashMap<usize, Finalizer<T>>,
+    pending:    Vec<(usize, Finalizer<T>)>,
+}
+
+impl<T> Finalizers<T> {
+    pub fn new() -> Finalizers<T> {
+        Finalizers {
+            registered: HashMap::new(),
+            pending:    Vec::new(),
+        }
+    }
+
+    /* Drop the finalizer of an object freed by hand */
+    pub fn forget(&mut self, handle: usize) {
+        self.registered.remove(&handle);
+    }
+}
+
+impl<T> fmt::Debug for Finalizers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Finalizers")
+            .field("registered", &self.registered.len())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl<T> Pile<T> {
+    /// Run `f` once a collection finds the object at `p` unreachable. The
+    /// object stays alive while `f` runs, and `f` may resurrect it. Replaces
+    /// the object's previous finalizer, if any. Gives `f` back if `p` does
+    /// not point to an object.
+    pub fn set_finalizer<F>(&mut self, p: Pointer, f: F) -> Result<(), F>
+        where F: FnOnce(Pointer, &mut Pile<T>) + 'static
+    {
+        if self.cell(p.handle).is_none() {
+            return Err(f);
+        }
+        self.finalizers.registered.insert(p.handle, Box::new(f));
+        Ok(())
+    }
+
+    /// Remove the finalizer of the object at `p` without running it.
+    /// Returns whether there was one.
+    pub fn clear_finalizer(&mut self, p: Pointer) -> bool {
+        self.finalizers.registered.remove(&p.handle).is_some()
+    }
+
+    /* Queue the finalizers of the white objects, and shade the objects so
+     * they survive until the finalizers have run */
+    pub(super) fn doom_white(&mut self) {
+        let dead: Vec<usize> = self.finalizers.registered.keys()
+            .cloned()
+            .filter(|&h| self.colour(h) == Some(Colour::White))
+            .collect();
+
+        for handle in dead {
+            let f = self.finalizers.registered.remove(&handle).unwrap();
+            self.finalizers.pending.push((handle, f));
+            self.shade(handle);
+        }
+    }
+
+    /* Take the finalizers of the young objects that `reachable` (indexed by
+     * nursery address) does not reach */
+    pub(super) fn doom_young(&mut self, reachable: &[bool])
+        -> Vec<(usize, Finalizer<T>)>
+    {
+        let handles = &self.handles;
+        let dead: Vec<usize> = self.finalizers.registered.keys()
+            .cloned()
+            .filter(|&h| match handles[h] {
+                Handle::Young { addr } => !reachable[addr],
+                _                      => false,
+            })
+            .collect();
+
+        dead.into_iter()
+            .map(|h| (h, self.finalizers.registered.remove(&h).unwrap()))
+            .collect()
+    }
+
+    /* Run the finalizers queued by the cycle that just finished */
+    pub(super) fn finalize_pending(&mut self) {
+        let pending = mem::take(&mut self.finalizers.pending);
+        self.finalize(pending);
+    }
+
+    pub(super) fn finalize(&mut self, doomed: Vec<(usize, Finalizer<T>)>) {
+        /* Collections started by the finalizers must not free the objects
+         * still waiting for theirs */
+        let _alive: Vec<Root<T>> = doomed.iter()
+            .map(|&(handle, _)| Root::new(Pointer { handle }, &self.roots))
+            .collect();
+
+        for (handle, f) in doomed {
+            f(Pointer { handle }, self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn runs_once_then_frees() {
+        let mut pile: Pile<u32> = Pile::new();
+        let p = pile.alloc(1).pointer();
+        let runs = Rc::new(Cell::new(0));
+        let r = runs.clone();
+        pile.set_finalizer(p, move |_, _| r.set(r.get() + 1)).ok().unwrap();
+
+        /* Kept alive for its finalizer, then freed */
+        assert_eq!(pile.collect(), 0);
+        assert_eq!(runs.get(), 1);
+        assert_eq!(pile.collect(), 1);
+        assert_eq!(runs.get(), 1);
+        assert!(pile.get(p).is_none());
+    }
+
+    #[test]
+    fn resurrect() {
+        let mut pile: Pile<u32> = Pile::new();
+        let p = pile.alloc(1).pointer();
+        let saved = Rc::new(RefCell::new(None));
+        let s = saved.clone();
+        pile.set_finalizer(p, move |p, pile| *s.borrow_mut() = pile.root(p)).ok().unwrap();
+
+        pile.collect();
+        pile.collect();
+        assert!(saved.borrow().is_some());
+        assert_eq!(*pile.get(p).unwrap(), 1);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn cleared() {
+        let mut pile: Pile<u32> = Pile::new();
+        let p = pile.alloc(1).pointer();
+        pile.set_finalizer(p, |_, _| panic!("cleared finalizer ran")).ok().unwrap();
+        assert!(pile.clear_finalizer(p));
+        assert_eq!(pile.collect(), 1);
+    }
+}
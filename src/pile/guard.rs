@@ -0,0 +1,99 @@
+/*
+ * Borrows of pile objects.
+ *
+ * The pile owns its objects, so borrowing one borrows the pile: a `PileRef`
+ * shares it, and a `PileRefMut` holds it exclusively. Neither can outlive a
+ * collection, so the collector is free to free and move objects.
+ *
+ * A mutable borrow may store new pointers in its object, so dropping it
+ * runs the write barrier for every pointer the object holds. Objects that
+ * need interior mutability can be kept as `RefCell`s, at the cost of
+ * calling `write_barrier` by hand after storing pointers through a shared
+ * borrow.
+ */
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use super::collect::Phase;
+use super::{Entry, Pile, Pointer, Trace};
+
+/// A shared borrow of an object in a pile.
+pub struct PileRef<'a, T: 'a> {
+    value: &'a T,
+}
+
+/// An exclusive borrow of an object in a pile. Applies the write barrier
+/// for the object's pointers when dropped.
+pub struct PileRefMut<'a, T: Trace + 'a> {
+    pile:   &'a mut Pile<T>,
+    handle: usize,
+}
+
+impl<'a, T> PileRef<'a, T> {
+    pub(super) fn new(value: &'a T) -> PileRef<'a, T> {
+        PileRef { value }
+    }
+}
+
+impl<'a, T: Trace> PileRefMut<'a, T> {
+    pub(super) fn new(pile: &'a mut Pile<T>, handle: usize) -> PileRefMut<'a, T> {
+        PileRefMut { pile, handle }
+    }
+}
+
+impl<'a, T> Deref for PileRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T: Trace> Deref for PileRefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self.pile.cell(self.handle) {
+            Some(Entry::Value { value, .. }) => value,
+            _                                => panic!("corrupt handle table"),
+        }
+    }
+}
+
+impl<'a, T: Trace> DerefMut for PileRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self.pile.cell_mut(self.handle) {
+            Some(Entry::Value { value, .. }) => value,
+            _                                => panic!("corrupt handle table"),
+        }
+    }
+}
+
+impl<'a, T: Trace> Drop for PileRefMut<'a, T> {
+    fn drop(&mut self) {
+        /* Nothing to record unless marking, or holding young objects */
+        if self.pile.phase != Phase::Mark && self.pile.nursery.is_empty() {
+            return;
+        }
+
+        let mut stored = Vec::new();
+        (**self).trace(&mut |p| stored.push(p));
+        let holder = Pointer { handle: self.handle };
+        for p in stored {
+            self.pile.write_barrier(holder, p);
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for PileRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, T: Trace + fmt::Debug> fmt::Debug for PileRefMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
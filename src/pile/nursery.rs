@@ -0,0 +1,181 @@
+/*
+ * Minor collections.
+ *
+ * New objects are bump allocated in the nursery. A minor collection traces
+ * the young objects reachable from the roots, and from old objects that
+ * point into the nursery, copies the survivors into memory and frees the
+ * rest. Copying only redirects the handle, so pointers never change.
+ *
+ * Old objects pointing to young ones are found through the remembered set,
+ * which the write barrier fills in, and so does allocating an object that
+ * did not fit in the nursery. Old objects are never traced otherwise,
+ * so a minor collection only touches the nursery and the remembered set.
+ *
+ * Ephemeron values are traced when their key is old, or young and found
+ * reachable. Dead young objects with finalizers are promoted like
+ * survivors, along with what they reach, and finalized once the nursery is
+ * empty.
+ */
+
+use std::mem;
+
+use super::{Entry, Handle, Pile, Pointer, Trace};
+
+impl<T> Pile<T> {
+    /* Record an old-to-young pointer for the next minor collection */
+    pub(super) fn remember(&mut self, holder: Pointer, stored: Pointer) {
+        let old = matches!(self.handles.get(holder.handle), Some(Handle::Used { .. }));
+        let young = matches!(self.handles.get(stored.handle), Some(Handle::Young { .. }));
+
+        if old && young {
+            self.remembered.insert(holder.handle);
+        }
+    }
+}
+
+impl<T: Trace> Pile<T> {
+    /// Promote the young objects that are still reachable to memory, and
+    /// free the others. Returns the number of objects freed.
+    pub fn collect_minor(&mut self) -> usize {
+        /* Young objects pointed to from the roots and from old objects */
+        let mut work = self.roots.borrow().handles();
+        for &holder in &self.remembered {
+            if let Some(Entry::Value { value, .. }) = self.cell(holder) {
+                value.trace(&mut |p| work.push(p.handle));
+            }
+        }
+
+        let mut reachable = vec![false; self.nursery.len()];
+        self.mark_young(work, &mut reachable);
+
+        let doomed = self.doom_young(&reachable);
+        self.mark_young(doomed.iter().map(|&(h, _)| h).collect(), &mut reachable);
+
+        /* Free the dead first, so memory only grows for the survivors */
+        let mut survivors = Vec::new();
+        let mut freed = 0;
+        for handle in 0..self.handles.len() {
+            if let Handle::Young { addr } = self.handles[handle] {
+                if reachable[addr] {
+                    survivors.push(handle);
+                } else {
+                    self.free(Pointer { handle });
+                    freed += 1;
+                }
+            }
+        }
+
+        let old = self.allocated - survivors.len();
+        let free = self.memory.len() - old;
+        if survivors.len() > free {
+            let len = self.memory.len().max(survivors.len() - free);
+            self.reserve(len);
+        }
+
+        for handle in survivors {
+            let addr = match self.handles[handle] {
+                Handle::Young { addr } => addr,
+                _                      => unreachable!(),
+            };
+            let entry = mem::replace(&mut self.nursery[addr], Entry::Free { next: None });
+            self.handles[handle] = Handle::Used { addr: self.promote(entry) };
+        }
+
+        self.nursery.clear();
+        self.remembered.clear();
+        self.prune_ephemerons();
+        debug_validate!(self);
+        self.finalize(doomed);
+        freed
+    }
+
+    /* Mark the young objects reachable from `work`, following young ones
+     * and the ephemeron values of keys found reachable */
+    fn mark_young(&mut self, mut work: Vec<usize>, reachable: &mut [bool]) {
+        loop {
+            while let Some(handle) = work.pop() {
+                let addr = match self.handles.get(handle) {
+                    Some(&Handle::Young { addr }) => addr,
+                    _                             => continue, // old, or freed
+                };
+                if reachable[addr] {
+                    continue;
+                }
+                reachable[addr] = true;
+
+                if let Entry::Value { ref value, .. } = self.nursery[addr] {
+                    value.trace(&mut |p| work.push(p.handle));
+                }
+            }
+
+            work = self.young_ephemeron_values(reachable);
+            work.retain(|&h| match self.handles.get(h) {
+                Some(&Handle::Young { addr }) => !reachable[addr],
+                _                             => false,
+            });
+            if work.is_empty() {
+                return;
+            }
+        }
+    }
+
+    /* Move a young cell into free memory, which must be available */
+    fn promote(&mut self, entry: Entry<T>) -> usize {
+        let i = self.free_head.expect("memory is reserved before promoting");
+        match self.memory[i] {
+            Entry::Free { next } => self.free_head = next,
+            Entry::Value { .. }  => panic!("corrupt free list"),
+        }
+        self.memory[i] = entry;
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Node = (u32, Option<Pointer>);
+
+    #[test]
+    fn promotes_survivors() {
+        let mut pile: Pile<Node> = Pile::with_nursery(8, 4);
+        let kept = pile.alloc((1, None));
+        let lost = pile.alloc((2, None)).pointer();
+        assert!(matches!(pile.handles[kept.pointer().handle], Handle::Young { .. }));
+
+        assert_eq!(pile.collect_minor(), 1);
+        assert!(matches!(pile.handles[kept.pointer().handle], Handle::Used { .. }));
+        assert_eq!(pile.get(kept.pointer()).unwrap().0, 1);
+        assert!(pile.get(lost).is_none());
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn old_to_young_pointers() {
+        let mut pile: Pile<Node> = Pile::with_nursery(8, 4);
+        let old = pile.alloc((1, None));
+        pile.collect_minor();
+
+        let young = pile.alloc((2, None)).pointer();
+        pile.get_mut(old.pointer()).unwrap().1 = Some(young);
+        assert_eq!(pile.collect_minor(), 0);
+        assert_eq!(pile.get(young).unwrap().0, 2);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn allocated_old_pointing_young() {
+        /* The nursery is full, so `old` goes straight to memory */
+        let mut pile: Pile<Node> = Pile::with_nursery(8, 1);
+        let young = pile.alloc((1, None));
+        let old = pile.alloc((2, Some(young.pointer())));
+        assert!(matches!(pile.handles[old.pointer().handle], Handle::Used { .. }));
+
+        let p = young.pointer();
+        drop(young);
+        assert_eq!(pile.collect_minor(), 0);
+        assert_eq!(pile.get(p).unwrap().0, 1);
+        assert_eq!(pile.validate(), Ok(()));
+    }
+}
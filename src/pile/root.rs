@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use super::Pointer;
+
+/* How many roots refer to each handle. Shared by a pile and its roots. */
+#[derive(Debug, Default)]
+pub struct RootSet {
+    counts: HashMap<usize, usize>,
+}
+
+impl RootSet {
+    fn add(&mut self, handle: usize) {
+        *self.counts.entry(handle).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, handle: usize) {
+        let gone = match self.counts.get_mut(&handle) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            },
+            None => panic!("corrupt root set"),
+        };
+
+        if gone {
+            self.counts.remove(&handle);
+        }
+    }
+
+    pub fn handles(&self) -> Vec<usize> {
+        self.counts.keys().cloned().collect()
+    }
+}
+
+/// A pointer held from outside the pile.
+///
+/// A root keeps its object (and everything reachable from it) alive through
+/// collections. It registers itself in the pile's root set when created and
+/// unregisters when dropped, so the collector always knows every root.
+/// Objects refer to each other with plain `Pointer`s, which are only kept
+/// alive by being traced from a root.
+pub struct Root<T> {
+    pointer: Pointer,
+    roots:   Rc<RefCell<RootSet>>,
+    _object: PhantomData<fn() -> T>,
+}
+
+impl<T> Root<T> {
+    pub(super) fn new(pointer: Pointer, roots: &Rc<RefCell<RootSet>>) -> Root<T> {
+        roots.borrow_mut().add(pointer.handle);
+        Root {
+            pointer,
+            roots:   roots.clone(),
+            _object: PhantomData,
+        }
+    }
+
+    /// The pointer to store inside other objects.
+    pub fn pointer(&self) -> Pointer {
+        self.pointer
+    }
+}
+
+impl<T> Clone for Root<T> {
+    fn clone(&self) -> Root<T> {
+        Root::new(self.pointer, &self.roots)
+    }
+}
+
+impl<T> Drop for Root<T> {
+    fn drop(&mut self) {
+        self.roots.borrow_mut().remove(self.pointer.handle);
+    }
+}
+
+impl<T> fmt::Debug for Root<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Root({:?})", self.pointer)
+    }
+}
@@ -0,0 +1,536 @@
+/*
+ * Binary snapshots of a Pile.
+ *
+ * Layout (all integers little endian):
+ *
+ *      magic       b"PILE"
+ *      version     u32
+ *      length      u64         number of payload bytes
+ *      payload     [u8]
+ *      checksum    u64         FNV-1a of the payload
+ *
+ * The payload encodes the handle table and the live cells:
+ *
+ *      handles     u64         size of the handle table
+ *      live        u64         number of live cells
+ *      cells       live * { handle: u64, generation: u32, length: u64, object: [u8] }
+ *      blocks      u64         number of byte blocks
+ *      bytes       blocks * { handle: u64, generation: u32, align: u64, length: u64, bytes: [u8] }
+ *
+ * Objects are encoded with `Persist`. Pointers inside objects are written
+ * as the handle they refer to; the loader compacts both the memory and the
+ * handle table, and redirects every pointer through `Trace::trace_mut`.
+ * Weak pointers are written with their generation, and redirected through
+ * `Trace::trace_weak_mut`; those that no longer match their cell are loaded
+ * dangling. Byte blocks are loaded behind the objects, and pointers to
+ * them are redirected the same way.
+ *
+ * Version 1 snapshots have no generations, and versions before 3 have no
+ * byte blocks; both are still read.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use std::alloc::Layout;
+
+use super::{Colour, Entry, Handle, Pile, Pointer, Trace, WeakPointer};
+
+const MAGIC: &[u8; 4] = b"PILE";
+const VERSION: u32 = 3;
+
+/* Bytes a cell takes before its object: handle, generation, length */
+const CELL_V1: usize = 8 + 8;
+const CELL_V2: usize = 8 + 4 + 8;
+
+/* Bytes a block takes before its bytes: handle, generation, align, length */
+const BLOCK: usize = 8 + 4 + 8 + 8;
+
+/// A value that can be written to and read back from a snapshot.
+pub trait Persist: Sized {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn restore(r: &mut dyn Read) -> io::Result<Self>;
+}
+
+/// Where the pointers of the saved pile ended up in the loaded one.
+#[derive(Debug)]
+pub struct Remap {
+    table: HashMap<usize, (usize, u32)>, /* saved handle -> loaded handle, saved generation */
+}
+
+impl Remap {
+    /// Translate a pointer into the saved pile to one into the loaded pile.
+    pub fn get(&self, p: Pointer) -> Option<Pointer> {
+        self.table.get(&p.handle).map(|&(handle, _)| Pointer { handle })
+    }
+
+    /// Translate a weak pointer into the saved pile. Returns `None` if its
+    /// object was already gone when the snapshot was written.
+    pub fn get_weak(&self, w: WeakPointer) -> Option<WeakPointer> {
+        match self.table.get(&w.handle) {
+            Some(&(handle, generation)) if generation == w.generation => {
+                Some(WeakPointer { handle, generation: 0 })
+            },
+            _ => None,
+        }
+    }
+}
+
+impl<T: Trace + Persist> Pile<T> {
+    pub fn write_snapshot(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut payload = Vec::new();
+        (self.handles.len() as u64).persist(&mut payload)?;
+        (self.allocated as u64).persist(&mut payload)?;
+
+        let mut object = Vec::new();
+        for handle in 0..self.handles.len() {
+            let value = match self.cell(handle) {
+                Some(Entry::Value { value, .. })      => value,
+                Some(&Entry::Free { .. })             => panic!("corrupt handle table"),
+                None                                  => continue,
+            };
+
+            object.clear();
+            value.persist(&mut object)?;
+
+            (handle as u64).persist(&mut payload)?;
+            self.generations[handle].persist(&mut payload)?;
+            (object.len() as u64).persist(&mut payload)?;
+            payload.extend_from_slice(&object);
+        }
+
+        (self.live_bytes() as u64).persist(&mut payload)?;
+        for handle in 0..self.handles.len() {
+            let block = match self.bytes.block(handle) {
+                Some(block) => block,
+                None        => continue,
+            };
+            (handle as u64).persist(&mut payload)?;
+            self.generations[handle].persist(&mut payload)?;
+            (block.align as u64).persist(&mut payload)?;
+            (block.len as u64).persist(&mut payload)?;
+            payload.extend_from_slice(self.bytes(Pointer { handle }).expect("block without bytes"));
+        }
+
+        w.write_all(MAGIC)?;
+        VERSION.persist(w)?;
+        (payload.len() as u64).persist(w)?;
+        w.write_all(&payload)?;
+        checksum(&payload).persist(w)
+    }
+
+    pub fn read_snapshot(r: &mut impl Read) -> io::Result<(Pile<T>, Remap)> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a pile snapshot"));
+        }
+        let version = u32::restore(r)?;
+        if version == 0 || version > VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        /* Read the payload without trusting the length up front */
+        let length = u64::restore(r)?;
+        let mut payload = Vec::new();
+        r.by_ref().take(length).read_to_end(&mut payload)?;
+        if payload.len() as u64 != length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated snapshot"));
+        }
+        if u64::restore(r)? != checksum(&payload) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let mut p: &[u8] = &payload;
+        let handles = u64::restore(&mut p)? as usize;
+        let live = u64::restore(&mut p)? as usize;
+        if live > handles {
+            return Err(invalid("more live cells than handles"));
+        }
+        /* Both come from the file: only allocate for as many cells as the
+         * payload can hold, and keep the table sparse */
+        let cell = if version >= 2 { CELL_V2 } else { CELL_V1 };
+        if live > p.len() / cell {
+            return Err(invalid("more live cells than the payload holds"));
+        }
+
+        /* Live cells are stored in handle order; compact them */
+        let mut table = HashMap::with_capacity(live);
+        let mut objects = Vec::with_capacity(live);
+        for _ in 0..live {
+            let handle = u64::restore(&mut p)? as usize;
+            let generation = if version >= 2 { u32::restore(&mut p)? } else { 0 };
+            let length = u64::restore(&mut p)? as usize;
+            if handle >= handles || table.contains_key(&handle) {
+                return Err(invalid("bad handle in snapshot"));
+            }
+            if length > p.len() {
+                return Err(invalid("object overruns payload"));
+            }
+
+            let (mut bytes, rest) = p.split_at(length);
+            p = rest;
+            table.insert(handle, (objects.len(), generation));
+            objects.push(T::restore(&mut bytes)?);
+            if !bytes.is_empty() {
+                return Err(invalid("object shorter than its length"));
+            }
+        }
+
+        /* Blocks get the handles after the objects */
+        let mut blocks = Vec::new();
+        if version >= 3 {
+            let count = u64::restore(&mut p)? as usize;
+            if count > handles - live || count > p.len() / BLOCK {
+                return Err(invalid("more blocks than the payload holds"));
+            }
+            for _ in 0..count {
+                let handle = u64::restore(&mut p)? as usize;
+                let generation = u32::restore(&mut p)?;
+                let align = u64::restore(&mut p)? as usize;
+                let length = u64::restore(&mut p)? as usize;
+                if handle >= handles || table.contains_key(&handle) {
+                    return Err(invalid("bad handle in snapshot"));
+                }
+                if length > p.len() {
+                    return Err(invalid("block overruns payload"));
+                }
+                let layout = match Layout::from_size_align(length, align) {
+                    Ok(layout) if align <= 4096 => layout,
+                    _                           => return Err(invalid("bad block layout")),
+                };
+
+                let (bytes, rest) = p.split_at(length);
+                p = rest;
+                table.insert(handle, (live + blocks.len(), generation));
+                blocks.push((layout, bytes));
+            }
+        }
+        if !p.is_empty() {
+            return Err(invalid("trailing bytes in snapshot"));
+        }
+
+        /* Redirect the pointers held by the objects */
+        let mut dangling = false;
+        for t in &mut objects {
+            t.trace_mut(&mut |ptr| match table.get(&ptr.handle) {
+                Some(&(handle, _)) => ptr.handle = handle,
+                None               => dangling = true,
+            });
+        }
+        if dangling {
+            return Err(invalid("dangling pointer in snapshot"));
+        }
+
+        let remap = Remap { table };
+        for t in &mut objects {
+            t.trace_weak_mut(&mut |w| {
+                *w = remap.get_weak(*w).unwrap_or_else(WeakPointer::dangling);
+            });
+        }
+
+        let mut pile = Pile::with_capacity(0);
+        pile.handles = (0..live).map(|addr| Handle::Used { addr })
+            .chain(blocks.iter().map(|_| Handle::Bytes))
+            .collect();
+        pile.generations = vec![0; live + blocks.len()];
+        pile.memory = objects.into_iter()
+            .map(|t| Entry::Value { value: t, colour: Colour::White })
+            .collect();
+        pile.allocated = live;
+        for (i, (layout, bytes)) in blocks.into_iter().enumerate() {
+            let handle = live + i;
+            pile.bytes.insert(handle, layout, Colour::White);
+            pile.bytes_mut(Pointer { handle }).expect("block was just inserted").copy_from_slice(bytes);
+        }
+
+        Ok((pile, remap))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/* FNV-1a, 64 bit */
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+macro_rules! impl_persist_int {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+                    w.write_all(&self.to_le_bytes())
+                }
+
+                fn restore(r: &mut dyn Read) -> io::Result<Self> {
+                    let mut buf = [0; ::std::mem::size_of::<$t>()];
+                    r.read_exact(&mut buf)?;
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    }
+}
+
+impl_persist_int!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/* usize and isize are always stored as 64 bits */
+impl Persist for usize {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as u64).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(u64::restore(r)? as usize)
+    }
+}
+
+impl Persist for isize {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as i64).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(i64::restore(r)? as isize)
+    }
+}
+
+impl Persist for () {
+    fn persist(&self, _: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(_: &mut dyn Read) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl Persist for bool {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as u8).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        match u8::restore(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bad bool")),
+        }
+    }
+}
+
+impl Persist for char {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (*self as u32).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        ::std::char::from_u32(u32::restore(r)?).ok_or_else(|| invalid("bad char"))
+    }
+}
+
+impl Persist for String {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.len() as u64).persist(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        let len = u64::restore(r)?;
+        let mut bytes = Vec::new();
+        r.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(invalid("truncated string"));
+        }
+        String::from_utf8(bytes).map_err(|_| invalid("bad utf-8"))
+    }
+}
+
+impl Persist for Pointer {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.handle as u64).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(Pointer { handle: u64::restore(r)? as usize })
+    }
+}
+
+impl Persist for WeakPointer {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.handle as u64).persist(w)?;
+        self.generation.persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(WeakPointer {
+            handle:     u64::restore(r)? as usize,
+            generation: u32::restore(r)?,
+        })
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        match *self {
+            None        => 0u8.persist(w),
+            Some(ref t) => {
+                1u8.persist(w)?;
+                t.persist(w)
+            },
+        }
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        match u8::restore(r)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::restore(r)?)),
+            _ => Err(invalid("bad option tag")),
+        }
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (self.len() as u64).persist(w)?;
+        for t in self {
+            t.persist(w)?;
+        }
+        Ok(())
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        let len = u64::restore(r)?;
+        /* Do not preallocate from an untrusted length */
+        let mut v = Vec::new();
+        for _ in 0..len {
+            v.push(T::restore(r)?);
+        }
+        Ok(v)
+    }
+}
+
+impl<T: Persist> Persist for RefCell<T> {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.borrow().persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(RefCell::new(T::restore(r)?))
+    }
+}
+
+impl<T: Persist> Persist for Box<T> {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        (**self).persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(Box::new(T::restore(r)?))
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn persist(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.0.persist(w)?;
+        self.1.persist(w)
+    }
+
+    fn restore(r: &mut dyn Read) -> io::Result<Self> {
+        Ok((A::restore(r)?, B::restore(r)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Node = (u32, Option<Pointer>);
+
+    fn header(handles: u64, live: u64, rest: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        handles.persist(&mut payload).unwrap();
+        live.persist(&mut payload).unwrap();
+        payload.extend_from_slice(rest);
+
+        let mut file = MAGIC.to_vec();
+        VERSION.persist(&mut file).unwrap();
+        (payload.len() as u64).persist(&mut file).unwrap();
+        file.extend_from_slice(&payload);
+        checksum(&payload).persist(&mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut pile: Pile<Node> = Pile::new();
+        let a = pile.alloc((1, None));
+        let b = pile.alloc((2, Some(a.pointer())));
+
+        let mut file = Vec::new();
+        pile.write_snapshot(&mut file).unwrap();
+        let (loaded, remap) = Pile::<Node>::read_snapshot(&mut &file[..]).unwrap();
+
+        let b2 = remap.get(b.pointer()).unwrap();
+        let next = loaded.get(b2).unwrap().1.unwrap();
+        assert_eq!(loaded.get(b2).unwrap().0, 2);
+        assert_eq!(next, remap.get(a.pointer()).unwrap());
+        assert_eq!(loaded.get(next).unwrap().0, 1);
+        assert_eq!(loaded.validate(), Ok(()));
+    }
+
+    #[test]
+    fn round_trip_bytes() {
+        let mut pile: Pile<Node> = Pile::new();
+        let block = pile.alloc_bytes(Layout::from_size_align(5, 16).unwrap());
+        pile.bytes_mut(block.pointer()).unwrap().copy_from_slice(b"hello");
+        let a = pile.alloc((1, Some(block.pointer())));
+
+        let mut file = Vec::new();
+        pile.write_snapshot(&mut file).unwrap();
+        let (loaded, remap) = Pile::<Node>::read_snapshot(&mut &file[..]).unwrap();
+
+        let a2 = remap.get(a.pointer()).unwrap();
+        let block2 = loaded.get(a2).unwrap().1.unwrap();
+        assert_eq!(block2, remap.get(block.pointer()).unwrap());
+        assert_eq!(loaded.bytes(block2), Some(&b"hello"[..]));
+        assert_eq!(loaded.validate(), Ok(()));
+    }
+
+    #[test]
+    fn corrupt_checksum() {
+        let pile: Pile<Node> = Pile::new();
+        let mut file = Vec::new();
+        pile.write_snapshot(&mut file).unwrap();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        assert!(Pile::<Node>::read_snapshot(&mut &file[..]).is_err());
+    }
+
+    #[test]
+    fn huge_counts() {
+        /* Must fail without trying to allocate for them */
+        let file = header(u64::MAX, u64::MAX, &[]);
+        let err = Pile::<Node>::read_snapshot(&mut &file[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let file = header(u64::MAX, 1 << 40, &[0; 64]);
+        let err = Pile::<Node>::read_snapshot(&mut &file[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        /* A huge handle table with few cells is fine */
+        let mut cell = Vec::new();
+        (u64::MAX - 1).persist(&mut cell).unwrap();
+        0u32.persist(&mut cell).unwrap();
+        0u64.persist(&mut cell).unwrap();
+        0u64.persist(&mut cell).unwrap(); /* no blocks */
+        let file = header(u64::MAX, 1, &cell);
+        assert!(Pile::<()>::read_snapshot(&mut &file[..]).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Automatic collection.
+ *
+ * Every allocation first checks the trigger: a full nursery runs a minor
+ * collection, and reaching the threshold, or being about to grow memory,
+ * runs a full one. After each full collection the heap-growth factor is
+ * adapted to how much it reclaimed: a collection that frees little means
+ * most of the heap is live, so the heap should grow faster (and collect
+ * less often) than after one that frees almost everything.
+ */
+
+use std::fmt;
+
+use super::{Pile, Trace};
+
+/// When a pile collects by itself, and how fast its heap grows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trigger {
+    /// Run a full collection once this many objects are allocated.
+    pub threshold:      Option<usize>,
+    /// Run a full collection instead of growing memory, when an allocation
+    /// finds it full.
+    pub before_growth:  bool,
+    /// Run a minor collection when the nursery is full.
+    pub minor:          bool,
+    /// Bounds of the heap-growth factor. Memory grows to `factor` times its
+    /// size, and the next threshold is `factor` times the live objects.
+    pub min_growth:     f64,
+    pub max_growth:     f64,
+}
+
+impl Trigger {
+    /// Never collect automatically, and double memory when it is full.
+    pub fn manual() -> Trigger {
+        Trigger {
+            threshold:      None,
+            before_growth:  false,
+            minor:          false,
+            min_growth:     2.0,
+            max_growth:     2.0,
+        }
+    }
+}
+
+impl Default for Trigger {
+    fn default() -> Trigger {
+        Trigger::manual()
+    }
+}
+
+/// Why an automatic collection ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Threshold,
+    Growth,
+    Nursery,
+}
+
+/// The report handed to `on_collect` hooks after an automatic collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collection {
+    pub reason: Reason,
+    pub before: usize,  /* objects allocated before collecting */
+    pub freed:  usize,
+    pub growth: f64,    /* heap-growth factor from now on */
+}
+
+type Hook = Box<dyn FnMut(&Collection)>;
+
+/* The trigger, and what it has adapted to so far */
+pub struct Auto {
+    trigger:    Trigger,
+    threshold:  Option<usize>,
+    growth:     f64,
+    hooks:      Vec<Hook>,
+}
+
+impl Auto {
+    pub fn new(trigger: Trigger) -> Auto {
+        Auto {
+            trigger,
+            threshold:  trigger.threshold,
+            growth:     trigger.max_growth,
+            hooks:      Vec::new(),
+        }
+    }
+
+    pub fn growth(&self) -> f64 {
+        self.growth
+    }
+}
+
+impl fmt::Debug for Auto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Auto")
+            .field("trigger", &self.trigger)
+            .field("threshold", &self.threshold)
+            .field("growth", &self.growth)
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+impl<T> Pile<T> {
+    /// Choose when the pile collects by itself.
+    pub fn set_trigger(&mut self, trigger: Trigger) {
+        self.auto.trigger = trigger;
+        self.auto.threshold = trigger.threshold;
+        self.auto.growth = trigger.max_growth;
+    }
+
+    /// Call `hook` after every automatic collection.
+    pub fn on_collect<F: FnMut(&Collection) + 'static>(&mut self, hook: F) {
+        self.auto.hooks.push(Box::new(hook));
+    }
+}
+
+impl<T: Trace> Pile<T> {
+    /* Run whatever collections the trigger asks for before an allocation */
+    pub(super) fn auto_collect(&mut self) {
+        let trigger = self.auto.trigger;
+
+        if trigger.minor && self.nursery_size > 0 && self.nursery.len() == self.nursery_size {
+            let before = self.allocated;
+            let freed = self.collect_minor();
+            self.report(Reason::Nursery, before, freed);
+        }
+
+        let young_full = self.nursery.len() == self.nursery_size;
+        let reason = match self.auto.threshold {
+            Some(t) if self.allocated >= t                    => Reason::Threshold,
+            _ if trigger.before_growth && young_full
+                && self.free_head.is_none()                   => Reason::Growth,
+            _                                                 => return,
+        };
+
+        let before = self.allocated;
+        let freed = self.collect();
+        self.adapt(before, freed);
+        self.report(reason, before, freed);
+    }
+
+    /* Grow faster the less the last collection reclaimed */
+    fn adapt(&mut self, before: usize, freed: usize) {
+        let trigger = self.auto.trigger;
+        let reclaimed = if before == 0 { 1.0 } else { freed as f64 / before as f64 };
+        let growth = trigger.max_growth - (trigger.max_growth - trigger.min_growth) * reclaimed;
+        self.auto.growth = growth;
+
+        if let Some(base) = trigger.threshold {
+            let next = (self.allocated as f64 * growth).ceil() as usize;
+            self.auto.threshold = Some(next.max(base).max(self.allocated + 1));
+        }
+    }
+
+    fn report(&mut self, reason: Reason, before: usize, freed: usize) {
+        let report = Collection { reason, before, freed, growth: self.auto.growth };
+        for hook in &mut self.auto.hooks {
+            hook(&report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn threshold() {
+        let mut pile: Pile<u32> = Pile::new();
+        pile.set_trigger(Trigger { threshold: Some(4), ..Trigger::manual() });
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let r = reports.clone();
+        pile.on_collect(move |c| r.borrow_mut().push(*c));
+
+        for i in 0..10 {
+            pile.alloc(i);
+        }
+        let reports = reports.borrow();
+        assert!(!reports.is_empty());
+        assert!(reports.iter().all(|c| c.reason == Reason::Threshold && c.freed == c.before));
+        assert!(pile.stats().live < 10);
+    }
+
+    #[test]
+    fn manual_never_collects() {
+        let mut pile: Pile<u32> = Pile::with_capacity(1);
+        for i in 0..100 {
+            pile.alloc(i);
+        }
+        assert_eq!(pile.stats().live, 100);
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ * Weak pointers.
+ *
+ * Every handle has a generation, which is bumped when the handle is given
+ * back. A weak pointer remembers the generation its object was allocated
+ * in, so once the object is freed (and its handle possibly reused) the weak
+ * pointer no longer matches, and upgrading it fails.
+ *
+ * Weak pointers are not traced, so they do not keep objects alive. During
+ * an incremental cycle an upgraded object is shaded, like a new root, and
+ * an object that is waiting to be swept can not be upgraded at all.
+ */
+
+use super::collect::Phase;
+use super::{Colour, Pile, PileRef, Pointer, Trace};
+
+/// A pointer that does not keep its object alive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WeakPointer {
+    pub(super) handle:      usize,
+    pub(super) generation:  u32,
+}
+
+impl WeakPointer {
+    /* Refers to no object, in any pile */
+    pub(super) fn dangling() -> WeakPointer {
+        WeakPointer {
+            handle:     usize::MAX,
+            generation: 0,
+        }
+    }
+}
+
+impl<T> Pile<T> {
+    /// A weak pointer to the object at `p`. Returns `None` if `p` does not
+    /// point to an object.
+    pub fn downgrade(&self, p: Pointer) -> Option<WeakPointer> {
+        self.cell(p.handle)?;
+        Some(WeakPointer {
+            handle:     p.handle,
+            generation: self.generations[p.handle],
+        })
+    }
+
+    /// The object `w` points to, if it is still alive.
+    pub fn upgrade(&mut self, w: WeakPointer) -> Option<Pointer> {
+        if self.generations.get(w.handle) != Some(&w.generation) {
+            return None;
+        }
+
+        match (self.phase, self.colour(w.handle)?) {
+            /* Already found dead, only not freed yet */
+            (Phase::Sweep { cursor, .. }, Colour::White) if w.handle >= cursor => None,
+            (Phase::Mark, _) => {
+                self.shade(w.handle);
+                Some(Pointer { handle: w.handle })
+            },
+            _ => Some(Pointer { handle: w.handle }),
+        }
+    }
+
+    /// Like `get`, for a weak pointer.
+    pub fn get_weak(&mut self, w: WeakPointer) -> Option<PileRef<'_, T>> {
+        let p = self.upgrade(w)?;
+        self.get(p)
+    }
+}
+
+impl Trace for WeakPointer {
+    fn trace(&self, _: &mut dyn FnMut(Pointer)) {}
+
+    fn trace_mut(&mut self, _: &mut dyn FnMut(&mut Pointer)) {}
+
+    fn trace_weak_mut(&mut self, tracer: &mut dyn FnMut(&mut WeakPointer)) {
+        tracer(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleared_once_collected() {
+        let mut pile: Pile<u32> = Pile::new();
+        let a = pile.alloc(1);
+        let w = pile.downgrade(a.pointer()).unwrap();
+        pile.collect();
+        assert_eq!(pile.upgrade(w), Some(a.pointer()));
+        assert_eq!(*pile.get_weak(w).unwrap(), 1);
+
+        drop(a);
+        assert_eq!(pile.collect(), 1);
+        assert_eq!(pile.upgrade(w), None);
+    }
+
+    #[test]
+    fn reused_handle() {
+        let mut pile: Pile<u32> = Pile::new();
+        let a = pile.alloc(1);
+        let w = pile.downgrade(a.pointer()).unwrap();
+        pile.free(a.pointer());
+        let b = pile.alloc(2);
+        assert_eq!(b.pointer(), a.pointer());
+        assert_eq!(pile.upgrade(w), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! A process allocator built from size-class free lists.
+//!
+//! Small requests are rounded up to a power of two between 8 and 2048
+//! bytes, and served from the free list of that size class. Like the free
+//! list in `FreeList`, it is threaded through the free blocks themselves:
+//! growing a class carves a fresh page into blocks that each link to the
+//! next, and the last one to the old head. Pages come from the system
+//! allocator, and are only given back when the allocator is dropped, which
+//! a global one never is. Larger requests go straight to the system
+//! allocator.
+//!
+//! Install it with
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: SizeClassAlloc = SizeClassAlloc::new();
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::UnsafeCell;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/* The smallest class must fit a free-list link */
+const MIN_CLASS: usize = 8;
+const CLASSES: usize = 9; /* 8, 16, ..., 2048 */
+const PAGE: usize = 64 * 1024;
+
+/* A free block; the link lives in the block itself */
+struct Free {
+    next: Option<NonNull<Free>>,
+}
+
+/* The first block of every page links to the page taken before it */
+struct Page {
+    next: Option<NonNull<Page>>,
+}
+
+struct Classes {
+    heads: [Option<NonNull<Free>>; CLASSES],
+    first: Option<NonNull<Page>>,  /* the page taken last */
+    pages: usize,
+    live:  usize,
+}
+
+pub struct SizeClassAlloc {
+    lock:    AtomicBool,
+    classes: UnsafeCell<Classes>,
+}
+
+/* All access to `classes` goes through the lock */
+unsafe impl Sync for SizeClassAlloc {}
+
+impl Default for SizeClassAlloc {
+    fn default() -> SizeClassAlloc {
+        SizeClassAlloc::new()
+    }
+}
+
+impl SizeClassAlloc {
+    pub const fn new() -> SizeClassAlloc {
+        SizeClassAlloc {
+            lock:    AtomicBool::new(false),
+            classes: UnsafeCell::new(Classes {
+                heads: [None; CLASSES],
+                first: None,
+                pages: 0,
+                live:  0,
+            }),
+        }
+    }
+
+    /// Number of pages taken from the system allocator so far.
+    pub fn pages(&self) -> usize {
+        self.with(|c| c.pages)
+    }
+
+    /// Number of small blocks in use.
+    pub fn live(&self) -> usize {
+        self.with(|c| c.live)
+    }
+
+    /* The class serving `layout`, if it is small enough. A block is
+     * aligned to its size, as pages are aligned to theirs. */
+    fn class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(MIN_CLASS).next_power_of_two();
+        let class = (size.trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize;
+        if class < CLASSES { Some(class) } else { None }
+    }
+
+    fn with<R, F: FnOnce(&mut Classes) -> R>(&self, f: F) -> R {
+        while self.lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            ::std::hint::spin_loop();
+        }
+        let _locked = Locked { lock: &self.lock };
+        f(unsafe { &mut *self.classes.get() })
+    }
+}
+
+/* Releases the lock when dropped, so a panic in `with` does not keep it */
+struct Locked<'a> {
+    lock: &'a AtomicBool,
+}
+
+impl<'a> Drop for Locked<'a> {
+    fn drop(&mut self) {
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+impl Classes {
+    fn alloc(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let block = match self.try_alloc(class) {
+            Some(block) => block,
+            None        => self.grow_and_alloc(class)?,
+        };
+        self.live += 1;
+        Some(block)
+    }
+
+    fn try_alloc(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let head = self.heads[class]?;
+        self.heads[class] = unsafe { head.as_ref().next };
+        Some(head.cast())
+    }
+
+    fn grow_and_alloc(&mut self, class: usize) -> Option<NonNull<u8>> {
+        self.grow(class)?;
+        Some(self.try_alloc(class).expect("allocating will always succeed after growing"))
+    }
+
+    /* Carve a new page into free blocks, but for the first one, which keeps
+     * the page on the list of pages; `None` if the system is out of memory */
+    fn grow(&mut self, class: usize) -> Option<()> {
+        let page = unsafe { System.alloc(Layout::from_size_align(PAGE, PAGE).unwrap()) };
+        let page = NonNull::new(page)?;
+        unsafe { (page.as_ptr() as *mut Page).write(Page { next: self.first }) };
+        self.first = Some(page.cast());
+
+        let size = MIN_CLASS << class;
+        let n = PAGE / size;
+        let old_head = self.heads[class];
+        for i in 1..n {
+            let block = unsafe { page.as_ptr().add(i * size) } as *mut Free;
+            let next = if i == n - 1 {
+                old_head
+            } else {
+                NonNull::new(unsafe { page.as_ptr().add((i + 1) * size) } as *mut Free)
+            };
+            unsafe { block.write(Free { next }) };
+        }
+
+        self.heads[class] = NonNull::new(unsafe { page.as_ptr().add(size) } as *mut Free);
+        self.pages += 1;
+        Some(())
+    }
+
+    unsafe fn free(&mut self, block: *mut u8, class: usize) {
+        let block = block as *mut Free;
+        block.write(Free { next: self.heads[class] });
+        self.heads[class] = NonNull::new(block);
+        self.live -= 1;
+    }
+}
+
+impl Drop for SizeClassAlloc {
+    fn drop(&mut self) {
+        let classes = self.classes.get_mut();
+        let mut page = classes.first.take();
+        while let Some(p) = page {
+            page = unsafe { p.as_ref().next };
+            unsafe { System.dealloc(p.as_ptr() as *mut u8, Layout::from_size_align(PAGE, PAGE).unwrap()) };
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for SizeClassAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match SizeClassAlloc::class(layout) {
+            Some(class) => match self.with(|c| c.alloc(class)) {
+                Some(block) => block.as_ptr(),
+                None        => ptr::null_mut(),
+            },
+            None => System.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        match SizeClassAlloc::class(layout) {
+            Some(class) => self.with(|c| c.free(block, class)),
+            None        => System.dealloc(block, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = Layout::from_size_align_unchecked(new_size, layout.align());
+        let class = SizeClassAlloc::class(layout);
+        match (class, SizeClassAlloc::class(new)) {
+            /* Still fits its block */
+            (Some(old), Some(new)) if old == new => block,
+            (None, None) => System.realloc(block, layout, new_size),
+            _ => {
+                let moved = self.alloc(new);
+                if !moved.is_null() {
+                    ptr::copy_nonoverlapping(block, moved, layout.size().min(new_size));
+                    self.dealloc(block, layout);
+                }
+                moved
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(SizeClassAlloc::class(layout(1, 1)), Some(0));
+        assert_eq!(SizeClassAlloc::class(layout(8, 8)), Some(0));
+        assert_eq!(SizeClassAlloc::class(layout(9, 1)), Some(1));
+        assert_eq!(SizeClassAlloc::class(layout(100, 4)), Some(4));
+        assert_eq!(SizeClassAlloc::class(layout(2048, 8)), Some(8));
+        assert_eq!(SizeClassAlloc::class(layout(2049, 8)), None);
+
+        /* Alignment counts as size */
+        assert_eq!(SizeClassAlloc::class(layout(8, 64)), Some(3));
+        assert_eq!(SizeClassAlloc::class(layout(8, 4096)), None);
+    }
+
+    #[test]
+    fn reuses_blocks_and_pages() {
+        let a = SizeClassAlloc::new();
+        let l = layout(24, 8);
+        unsafe {
+            let p = a.alloc(l);
+            assert_eq!(p as usize % 32, 0);
+            a.dealloc(p, l);
+            assert_eq!(a.alloc(l), p);
+            a.dealloc(p, l);
+        }
+        assert_eq!(a.pages(), 1);
+        assert_eq!(a.live(), 0);
+
+        /* The first block of a page keeps the page list */
+        let l = layout(2048, 8);
+        let per_page = PAGE / 2048 - 1;
+        let blocks: Vec<_> = (0..2 * per_page).map(|_| unsafe { a.alloc(l) }).collect();
+        assert_eq!(a.pages(), 3);
+        assert_eq!(a.live(), 2 * per_page);
+        for p in blocks {
+            unsafe { a.dealloc(p, l) };
+        }
+        let again: Vec<_> = (0..2 * per_page).map(|_| unsafe { a.alloc(l) }).collect();
+        assert_eq!(a.pages(), 3);
+        for p in again {
+            unsafe { a.dealloc(p, l) };
+        }
+    }
+
+    #[test]
+    fn large_requests_go_to_the_system() {
+        let a = SizeClassAlloc::new();
+        let l = layout(PAGE, 16);
+        unsafe {
+            let p = a.alloc(l);
+            assert!(!p.is_null());
+            p.write_bytes(1, PAGE);
+            let p = a.realloc(p, l, 2 * PAGE);
+            assert_eq!(*p.add(PAGE - 1), 1);
+            a.dealloc(p, layout(2 * PAGE, 16));
+        }
+        assert_eq!(a.pages(), 0);
+        assert_eq!(a.live(), 0);
+    }
+
+    #[test]
+    fn realloc_between_classes() {
+        let a = SizeClassAlloc::new();
+        unsafe {
+            let p = a.alloc(layout(8, 8));
+            p.write_bytes(7, 8);
+            assert_eq!(a.realloc(p, layout(8, 8), 6), p);
+            let q = a.realloc(p, layout(6, 8), 64);
+            assert_eq!(*q.add(5), 7);
+            let r = a.realloc(q, layout(64, 8), 4096);
+            assert_eq!(*r.add(5), 7);
+            a.dealloc(r, layout(4096, 8));
+        }
+        assert_eq!(a.live(), 0);
+    }
+
+    #[test]
+    fn panic_releases_the_lock() {
+        let a = SizeClassAlloc::new();
+        let r = panic::catch_unwind(AssertUnwindSafe(|| a.with(|_| panic!("inside the lock"))));
+        assert!(r.is_err());
+        assert_eq!(a.live(), 0);
+    }
+}
@@ -0,0 +1,325 @@
+use std::alloc::{self, Layout};
+#[cfg(debug_assertions)]
+use std::collections::HashSet;
+use std::ptr::NonNull;
+
+use invariant::{self, Violation};
+use stats::{self, Counters, Stats};
+
+/// Hands out raw blocks of one size and alignment, e.g. for FFI buffers.
+///
+/// Like `FreeList`, the free list is threaded through the unused blocks
+/// themselves, so it costs no memory of its own. Blocks live in chunks that
+/// are never moved or given back before the slab is dropped, so a block
+/// stays put until it is freed. Debug builds also remember which blocks are
+/// handed out, to catch double frees and pointers from elsewhere.
+#[derive(Debug)]
+pub struct Slab {
+    block:  Layout,             /* of one block, padded to its alignment */
+    chunks: Vec<Chunk>,
+    head:   Option<NonNull<u8>>,
+    len:    usize,
+    #[cfg(debug_assertions)]
+    taken:  HashSet<usize>,
+    counters: Counters,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    base:   NonNull<u8>,
+    blocks: usize,
+    layout: Layout,
+}
+
+const DEFAULT_CAPACITY: usize = 16;
+
+impl Slab {
+    /// A slab of blocks fitting `layout`.
+    pub fn new(layout: Layout) -> Slab {
+        Slab::with_capacity(layout, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(layout: Layout, n: usize) -> Slab {
+        /* A free block must hold a link */
+        let link = Layout::new::<Option<NonNull<u8>>>();
+        let size = layout.size().max(link.size());
+        let align = layout.align().max(link.align());
+        let block = Layout::from_size_align(size, align)
+            .expect("block layout overflows")
+            .pad_to_align();
+
+        let mut slab = Slab {
+            block,
+            chunks: Vec::new(),
+            head:   None,
+            len:    0,
+            #[cfg(debug_assertions)]
+            taken:  HashSet::new(),
+            counters: Counters::new(),
+        };
+        slab.grow(n);
+        slab
+    }
+
+    /// The layout of the blocks; at least the one asked for.
+    pub fn block_layout(&self) -> Layout {
+        self.block
+    }
+
+    pub fn alloc(&mut self) -> NonNull<u8> {
+        let p = match self.try_alloc() {
+            Some(p) => p,
+            None    => self.grow_and_alloc(),
+        };
+        #[cfg(debug_assertions)]
+        self.taken.insert(p.as_ptr() as usize);
+        self.counters.insert(self.len);
+        debug_validate!(self);
+        p
+    }
+
+    /// Give a block back. In debug builds, panics if `p` was not handed out
+    /// by this slab, or was already freed.
+    ///
+    /// # Safety
+    ///
+    /// `p` must have been returned by `alloc` on this slab, and not been
+    /// freed since. Release builds do not check, and the next allocations
+    /// would write through a foreign pointer, or hand out a block twice.
+    pub unsafe fn free(&mut self, p: NonNull<u8>) {
+        #[cfg(debug_assertions)]
+        {
+            if self.index(p).is_none() {
+                panic!("{:p} is not a block of this slab", p);
+            }
+            if !self.taken.remove(&(p.as_ptr() as usize)) {
+                panic!("double free of {:p}", p);
+            }
+        }
+
+        unsafe { self.link(p).write(self.head) };
+        self.head = Some(p);
+        self.len -= 1;
+        self.counters.remove();
+        debug_validate!(self);
+    }
+
+    /// Whether `p` is the start of a block of this slab, free or not.
+    pub fn contains(&self, p: NonNull<u8>) -> bool {
+        self.index(p).is_some()
+    }
+
+    /// Add a chunk of `n` blocks. Panics if the chunk would not fit in the
+    /// address space.
+    pub fn grow(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let layout = self.block.size().checked_mul(n)
+            .and_then(|size| Layout::from_size_align(size, self.block.align()).ok())
+            .expect("chunk layout overflows");
+        let base = match NonNull::new(unsafe { alloc::alloc(layout) }) {
+            Some(base) => base,
+            None       => alloc::handle_alloc_error(layout),
+        };
+
+        /* Thread the new blocks onto the free list, the last one linking
+         * to the old head */
+        let old_head = self.head;
+        for i in 0..n {
+            let block = unsafe { NonNull::new_unchecked(base.as_ptr().add(i * self.block.size())) };
+            let next = if i == n - 1 {
+                old_head
+            } else {
+                Some(unsafe { NonNull::new_unchecked(block.as_ptr().add(self.block.size())) })
+            };
+            unsafe { self.link(block).write(next) };
+        }
+
+        self.chunks.push(Chunk { base, blocks: n, layout });
+        self.head = Some(base);
+        self.counters.grow();
+        debug_validate!(self);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let capacity = self.capacity();
+        let mut free = vec![false; capacity];
+        let mut block = self.head;
+        while let Some(p) = block {
+            free[self.index(p).expect("corrupt free list")] = true;
+            block = unsafe { self.link(p).read() };
+        }
+
+        let mut stats = Stats {
+            live:               self.len,
+            capacity,
+            free:               capacity - self.len,
+            largest_free_run:   stats::largest_run(free),
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check the free list: it must only link blocks of this slab, never
+    /// loop, and together with the handed out blocks account for all.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let capacity = self.capacity();
+        let head = self.head.map(|p| self.index(p).unwrap_or(capacity));
+        let free = invariant::walk("slab", head, capacity, |i| {
+            let block = self.block(i);
+            if self.is_taken(block) {
+                return None;
+            }
+            let next = unsafe { self.link(block).read() };
+            Some(next.map(|p| self.index(p).unwrap_or(capacity)))
+        })?;
+
+        let free = free.iter().filter(|&&f| f).count();
+        invariant::count("slab", self.len, free, capacity)
+    }
+
+    fn try_alloc(&mut self) -> Option<NonNull<u8>> {
+        let p = self.head?;
+        self.head = unsafe { self.link(p).read() };
+        self.len += 1;
+        Some(p)
+    }
+
+    fn grow_and_alloc(&mut self) -> NonNull<u8> {
+        /* Double the capacity */
+        let n = self.capacity().max(1);
+        self.grow(n);
+        self.try_alloc().expect("allocating will always succeed after growing")
+    }
+
+    /* Only known in debug builds */
+    #[cfg(debug_assertions)]
+    fn is_taken(&self, p: NonNull<u8>) -> bool {
+        self.taken.contains(&(p.as_ptr() as usize))
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn is_taken(&self, _: NonNull<u8>) -> bool {
+        false
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.iter().map(|c| c.blocks).sum()
+    }
+
+    /* Where the link of a free block lives: at its start */
+    fn link(&self, p: NonNull<u8>) -> *mut Option<NonNull<u8>> {
+        p.as_ptr() as *mut Option<NonNull<u8>>
+    }
+
+    /* Number blocks across chunks, in the order the chunks were added */
+    fn index(&self, p: NonNull<u8>) -> Option<usize> {
+        let addr = p.as_ptr() as usize;
+        let mut first = 0;
+        for c in &self.chunks {
+            let base = c.base.as_ptr() as usize;
+            let offset = addr.wrapping_sub(base);
+            if addr >= base && offset < c.blocks * self.block.size() {
+                return if offset.is_multiple_of(self.block.size()) {
+                    Some(first + offset / self.block.size())
+                } else {
+                    None
+                };
+            }
+            first += c.blocks;
+        }
+        None
+    }
+
+    fn block(&self, index: usize) -> NonNull<u8> {
+        let mut i = index;
+        for c in &self.chunks {
+            if i < c.blocks {
+                return unsafe { NonNull::new_unchecked(c.base.as_ptr().add(i * self.block.size())) };
+            }
+            i -= c.blocks;
+        }
+        panic!("block {} is out of bounds", index)
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        for c in &self.chunks {
+            unsafe { alloc::dealloc(c.base.as_ptr(), c.layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_freed_blocks() {
+        let mut slab = Slab::with_capacity(Layout::new::<[u32; 3]>(), 2);
+        let blocks: Vec<_> = (0..10).map(|_| slab.alloc()).collect();
+        assert_eq!(slab.stats().live, 10);
+        for &p in &blocks[..5] {
+            unsafe { slab.free(p) };
+        }
+        assert_eq!(slab.validate(), Ok(()));
+
+        let again: Vec<_> = (0..5).map(|_| slab.alloc()).collect();
+        for p in &again {
+            assert!(blocks[..5].contains(p));
+        }
+        assert_eq!(slab.stats().capacity, slab.stats().live + slab.stats().free);
+        assert_eq!(slab.validate(), Ok(()));
+    }
+
+    #[test]
+    fn blocks_fit_their_layout() {
+        let layout = Layout::from_size_align(3, 64).unwrap();
+        let mut slab = Slab::new(layout);
+        for _ in 0..40 {
+            let p = slab.alloc();
+            assert!((p.as_ptr() as usize).is_multiple_of(64));
+            assert!(slab.contains(p));
+        }
+        assert!(slab.block_layout().size() >= 3);
+    }
+
+    #[test]
+    fn validate_finds_cycle() {
+        let slab = Slab::with_capacity(Layout::new::<u64>(), 4);
+        let head = slab.head.unwrap();
+        unsafe { slab.link(head).write(Some(head)) };
+        assert!(slab.validate().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk layout overflows")]
+    fn huge_chunk() {
+        let mut slab = Slab::with_capacity(Layout::new::<[u64; 4]>(), 1);
+        slab.grow(usize::MAX / 8);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let mut slab = Slab::new(Layout::new::<u64>());
+        let p = slab.alloc();
+        unsafe {
+            slab.free(p);
+            slab.free(p);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is not a block of this slab")]
+    fn foreign_pointer() {
+        let mut slab = Slab::new(Layout::new::<u64>());
+        let mut x = 0u64;
+        unsafe { slab.free(NonNull::from(&mut x).cast()) };
+    }
+}
@@ -6,18 +6,6 @@ pub struct HandleMap<T> {
     free_slots: Vec<usize>
 }
 
-pub struct HandleMap2<T> {
-    data:       Vec<Entry<T>>,
-    slots:      Vec<Slot>,
-    free_data:  Option<usize>,
-    free_slots: Option<usize>
-}
-
-pub enum Entry<T> {
-    Free  { next: Option<usize> },
-    Taken { value: T },
-}
-
 #[derive(Debug)]
 struct Slot {
     generation: usize, /* used to invalidate refrences */
@@ -30,6 +18,12 @@ pub struct Handle {
     slot:       usize, /* address in the handles vector */
 }
 
+impl<T> Default for HandleMap<T> {
+    fn default() -> HandleMap<T> {
+        HandleMap::new()
+    }
+}
+
 impl<T> HandleMap<T> {
     pub fn new() -> HandleMap<T> {
         HandleMap {
@@ -64,10 +58,10 @@ impl<T> HandleMap<T> {
                 address:    addr,
             };
 
-            return Handle {
+            Handle {
                 generation: current_gen,
                 slot:       i,
-            };
+            }
         } else {
             /* No reusable slots */
             self.slots.push(Slot {
@@ -75,18 +69,18 @@ impl<T> HandleMap<T> {
                 address:    addr,
             });
 
-            return Handle {
+            Handle {
                 generation: 1,
                 slot:       self.slots.len() - 1,
-            };
-        };
+            }
+        }
     }
 
     pub fn remove(&mut self, h: Handle) {
         let generation = self.slots[h.slot].generation;
 
         if h.generation != generation {
-            return ();
+            return;
         }
 
         let address = self.slots[h.slot].address;
@@ -0,0 +1,166 @@
+/// A snapshot of how a container uses its memory.
+///
+/// `live`, `capacity`, `free` and `largest_free_run` are always available.
+/// The event counters (`high_water`, `grows`, `inserts`, `removes`) are only
+/// kept when the `stats` feature is enabled, and read as zero otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub live:               usize, /* entries in use */
+    pub capacity:           usize, /* entries in the backing storage */
+    pub free:               usize, /* entries waiting to be reused */
+    pub largest_free_run:   usize, /* longest stretch of neighbouring free entries */
+    pub high_water:         usize, /* most entries ever in use at once */
+    pub grows:              usize, /* times the backing storage grew */
+    pub inserts:            usize,
+    pub removes:            usize,
+}
+
+impl Stats {
+    /// How scattered the free entries are: 0 when they form a single run,
+    /// approaching 1 as they are spread out one by one.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_run as f64 / self.free as f64
+        }
+    }
+}
+
+/// Length of the longest run of `true` in `free`.
+pub(crate) fn largest_run<I: IntoIterator<Item = bool>>(free: I) -> usize {
+    let mut largest = 0;
+    let mut run = 0;
+    for f in free {
+        run = if f { run + 1 } else { 0 };
+        largest = largest.max(run);
+    }
+    largest
+}
+
+/* The event counters kept by every container */
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Counters {
+    high_water: usize,
+    grows:      usize,
+    inserts:    usize,
+    removes:    usize,
+}
+
+#[cfg(feature = "stats")]
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    pub fn insert(&mut self, live: usize) {
+        self.inserts += 1;
+        self.high_water = self.high_water.max(live);
+    }
+
+    pub fn remove(&mut self) {
+        self.removes += 1;
+    }
+
+    pub fn grow(&mut self) {
+        self.grows += 1;
+    }
+
+    pub fn fill(&self, stats: &mut Stats) {
+        stats.high_water = self.high_water;
+        stats.grows = self.grows;
+        stats.inserts = self.inserts;
+        stats.removes = self.removes;
+    }
+}
+
+/* Without the feature the counters take no space and compile to nothing */
+#[cfg(not(feature = "stats"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Counters;
+
+#[cfg(not(feature = "stats"))]
+impl Counters {
+    pub fn new() -> Counters {
+        Counters
+    }
+
+    #[inline]
+    pub fn insert(&mut self, _live: usize) {}
+
+    #[inline]
+    pub fn remove(&mut self) {}
+
+    #[inline]
+    pub fn grow(&mut self) {}
+
+    #[inline]
+    pub fn fill(&self, _stats: &mut Stats) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlemap2::HandleMap2;
+
+    fn with_free(free: usize, largest_free_run: usize) -> Stats {
+        Stats { capacity: 8, live: 8 - free, free, largest_free_run, ..Stats::default() }
+    }
+
+    #[test]
+    fn fragmentation() {
+        assert_eq!(Stats::default().fragmentation(), 0.0);
+        assert_eq!(with_free(0, 0).fragmentation(), 0.0);
+        assert_eq!(with_free(8, 8).fragmentation(), 0.0);
+        assert_eq!(with_free(4, 1).fragmentation(), 0.75);
+        assert_eq!(with_free(4, 2).fragmentation(), 0.5);
+    }
+
+    #[test]
+    fn largest_runs() {
+        assert_eq!(largest_run(Vec::new()), 0);
+        assert_eq!(largest_run(vec![false; 4]), 0);
+        assert_eq!(largest_run(vec![true; 4]), 4);
+        assert_eq!(largest_run(vec![true, false, true, true, false, true]), 2);
+        assert_eq!(largest_run(vec![false, true, false, true, true, true]), 3);
+    }
+
+    #[test]
+    fn interleaved_container() {
+        let mut m = HandleMap2::new();
+        let handles: Vec<_> = (0..8).map(|i| m.insert(i)).collect();
+        for h in handles.iter().step_by(2) {
+            m.remove(*h);
+        }
+        let stats = m.stats();
+        assert_eq!((stats.live, stats.free, stats.largest_free_run), (4, 4, 1));
+        assert_eq!(stats.fragmentation(), 0.75);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn counters_reach_stats() {
+        let mut m = HandleMap2::new();
+        let a = m.insert(1);
+        let b = m.insert(2);
+        m.remove(a);
+        m.insert(3);
+        m.remove(b);
+
+        let stats = m.stats();
+        assert_eq!(stats.inserts, 3);
+        assert_eq!(stats.removes, 2);
+        assert_eq!(stats.high_water, 2);
+        assert!(stats.grows >= 1);
+    }
+
+    #[cfg(not(feature = "stats"))]
+    #[test]
+    fn counters_read_zero() {
+        let mut m = HandleMap2::new();
+        m.insert(1);
+        let stats = m.stats();
+        assert_eq!((stats.inserts, stats.removes, stats.high_water, stats.grows), (0, 0, 0, 0));
+    }
+}
@@ -0,0 +1,473 @@
+use std::fmt;
+
+use invariant::{self, Violation};
+use stats::{Counters, Stats};
+
+/// A two-level segregated fit allocator over a preallocated pool.
+///
+/// Every operation takes constant time, so it is fit for real-time threads:
+/// the pool never grows, and `alloc` returns `None` when no free block is
+/// large enough. Free blocks are kept on segregated free lists: the first
+/// level splits sizes by powers of two, the second level splits every power
+/// of two in `SL` equal ranges. A bitmap per level tells which lists are
+/// non-empty, so finding a list with a block that fits is a couple of bit
+/// scans. A freed block is merged with its free neighbours right away.
+///
+/// Every block starts with a header holding its size and the offset of the
+/// block before it in memory. Like in `FreeList`, the free lists are threaded
+/// through the free blocks themselves, right after the header. The header of
+/// a block that is merged into another is cleared, so freeing it again is
+/// caught.
+pub struct Tlsf {
+    memory:     Vec<u8>,
+    fl_bitmap:  u32,                    /* first levels with a non-empty list */
+    sl_bitmap:  [u32; FL],              /* per first level, non-empty lists */
+    heads:      [[Option<usize>; SL]; FL],
+    live:       usize,                  /* bytes in blocks handed out, headers included */
+    counters:   Counters,
+}
+
+/// A block handed out by a `Tlsf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    offset: usize, /* of the header */
+}
+
+/* Offsets and sizes of blocks are multiples of GRAIN */
+const GRAIN: usize = 16;
+const HEADER: usize = 16;           /* size and free bit, offset of the block before */
+const MIN_BLOCK: usize = 32;        /* a header and two free-list links */
+
+const SL_LOG: u32 = 4;
+const SL: usize = 1 << SL_LOG;
+const FL: usize = 32;
+
+const FREE: usize = 1;
+const NIL: u64 = u64::MAX;
+
+impl Tlsf {
+    /// A pool of `size` bytes, rounded down to a multiple of 16. This is
+    /// the only allocation the `Tlsf` ever makes. Panics if the pool is too
+    /// small to hold a block, or too large to index.
+    pub fn new(size: usize) -> Tlsf {
+        let size = size / GRAIN * GRAIN;
+        assert!(size >= MIN_BLOCK, "pool of {} bytes can not hold a block", size);
+        assert!(Tlsf::mapping(size).0 < FL, "pool of {} bytes is too large", size);
+
+        let mut tlsf = Tlsf {
+            memory:     vec![0; size],
+            fl_bitmap:  0,
+            sl_bitmap:  [0; FL],
+            heads:      [[None; SL]; FL],
+            live:       0,
+            counters:   Counters::new(),
+        };
+        tlsf.set_header(0, size, true);
+        tlsf.set_prev_phys(0, None);
+        tlsf.push(0);
+        tlsf
+    }
+
+    /// Allocate a block of at least `size` bytes. Returns `None` if no free
+    /// block is large enough.
+    pub fn alloc(&mut self, size: usize) -> Option<Block> {
+        if size > self.memory.len() {
+            return None;
+        }
+        let needed = (align_up(size, GRAIN) + HEADER).max(MIN_BLOCK);
+        let (fl, sl) = self.find(needed)?;
+        let offset = self.heads[fl][sl].expect("corrupt free list");
+        self.unlink(offset);
+
+        /* Give the rest back, if it makes a block of its own */
+        let total = self.size(offset);
+        if total - needed >= MIN_BLOCK {
+            let rest = offset + needed;
+            self.set_header(offset, needed, false);
+            self.set_header(rest, total - needed, true);
+            self.set_prev_phys(rest, Some(offset));
+            if let Some(next) = self.next_phys(rest) {
+                self.set_prev_phys(next, Some(rest));
+            }
+            self.push(rest);
+        } else {
+            self.set_header(offset, total, false);
+        }
+
+        self.live += self.size(offset);
+        self.counters.insert(self.live);
+        debug_validate!(self);
+        Some(Block { offset })
+    }
+
+    /// Give a block back, merging it with the free blocks around it.
+    /// Panics if the block is already free.
+    pub fn free(&mut self, block: Block) {
+        let mut offset = block.offset;
+        if !self.is_block(offset) || self.is_free(offset) {
+            panic!("double free of {:?}", block);
+        }
+        let mut size = self.size(offset);
+        let next = self.next_phys(offset);
+        self.live -= size;
+
+        if let Some(prev) = self.prev_phys(offset) {
+            if self.is_free(prev) {
+                self.unlink(prev);
+                size += self.size(prev);
+                offset = prev;
+                self.set_word(block.offset, 0);
+            }
+        }
+        if let Some(next) = next {
+            if self.is_free(next) {
+                self.unlink(next);
+                size += self.size(next);
+                self.set_word(next, 0);
+            }
+        }
+
+        self.set_header(offset, size, true);
+        if let Some(next) = self.next_phys(offset) {
+            self.set_prev_phys(next, Some(offset));
+        }
+        self.push(offset);
+
+        self.counters.remove();
+        debug_validate!(self);
+    }
+
+    /// The usable bytes of a block; at least as many as were asked for.
+    pub fn bytes(&self, block: Block) -> &[u8] {
+        let end = block.offset + self.size(block.offset);
+        &self.memory[block.offset + HEADER..end]
+    }
+
+    pub fn bytes_mut(&mut self, block: Block) -> &mut [u8] {
+        let end = block.offset + self.size(block.offset);
+        &mut self.memory[block.offset + HEADER..end]
+    }
+
+    /// Memory statistics, counted in bytes, headers included.
+    pub fn stats(&self) -> Stats {
+        let capacity = self.memory.len();
+        let mut largest = 0;
+        let mut offset = Some(0);
+        while let Some(o) = offset {
+            if self.is_free(o) {
+                largest = largest.max(self.size(o));
+            }
+            offset = self.next_phys(o);
+        }
+
+        let mut stats = Stats {
+            live:               self.live,
+            capacity,
+            free:               capacity - self.live,
+            largest_free_run:   largest,
+            ..Stats::default()
+        };
+        self.counters.fill(&mut stats);
+        stats
+    }
+
+    /// Check that the blocks tile the pool with no two free blocks next to
+    /// each other, and that every free list holds free blocks of its size
+    /// range. Lists missing from the bitmaps are not counted, so a stray
+    /// bit shows up as free memory that can not be found.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let len = self.memory.len();
+        let mut prev: Option<usize> = None;
+        let mut offset = 0;
+        while offset < len {
+            let size = self.size(offset);
+            if size < MIN_BLOCK || !size.is_multiple_of(GRAIN) || offset + size > len
+                || self.prev_phys(offset) != prev
+            {
+                return Err(Violation::Overlap { list: "tlsf", offset });
+            }
+            if self.is_free(offset) && prev.is_some_and(|p| self.is_free(p)) {
+                return Err(Violation::Uncoalesced { list: "tlsf", offset });
+            }
+            prev = Some(offset);
+            offset += size;
+        }
+
+        let mut free = 0;
+        for fl in 0..FL {
+            for sl in 0..SL {
+                if self.fl_bitmap & (1 << fl) == 0 || self.sl_bitmap[fl] & (1 << sl) == 0 {
+                    continue;
+                }
+                let head = self.heads[fl][sl].map(|o| o / GRAIN);
+                let on_list = invariant::walk("tlsf", head, len / GRAIN, |i| {
+                    let o = i * GRAIN;
+                    if self.is_free(o) && Tlsf::mapping(self.size(o)) == (fl, sl) {
+                        Some(self.next_free(o).map(|o| o / GRAIN))
+                    } else {
+                        None
+                    }
+                })?;
+                free += on_list.iter()
+                    .enumerate()
+                    .filter(|&(_, &f)| f)
+                    .map(|(i, _)| self.size(i * GRAIN))
+                    .sum::<usize>();
+            }
+        }
+
+        invariant::count("tlsf", self.live, free, len)
+    }
+
+    /* The list a free block of `size` bytes goes on */
+    fn mapping(size: usize) -> (usize, usize) {
+        let units = size / GRAIN;
+        if units < SL {
+            (0, units)
+        } else {
+            let log = usize::BITS - 1 - units.leading_zeros();
+            let fl = (log - SL_LOG + 1) as usize;
+            let sl = (units >> (log - SL_LOG)) - SL;
+            (fl, sl)
+        }
+    }
+
+    /* A non-empty list whose blocks all fit `size` bytes: round the size up
+     * to the next list, then look there or further up */
+    fn find(&self, size: usize) -> Option<(usize, usize)> {
+        let units = size / GRAIN;
+        let rounded = if units < SL {
+            size
+        } else {
+            let log = usize::BITS - 1 - units.leading_zeros();
+            size + (((1 << (log - SL_LOG)) - 1) * GRAIN)
+        };
+        let (fl, sl) = Tlsf::mapping(rounded);
+        if fl >= FL {
+            return None;
+        }
+
+        let sl_map = self.sl_bitmap[fl] & (!0u32).checked_shl(sl as u32).unwrap_or(0);
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
+        let fl_map = self.fl_bitmap & (!0u32).checked_shl(fl as u32 + 1).unwrap_or(0);
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    /* The free lists: doubly linked, so a neighbour can be taken out of the
+     * middle of its list when merging */
+
+    fn push(&mut self, offset: usize) {
+        let (fl, sl) = Tlsf::mapping(self.size(offset));
+        let head = self.heads[fl][sl];
+        self.set_next_free(offset, head);
+        self.set_prev_free(offset, None);
+        if let Some(h) = head {
+            self.set_prev_free(h, Some(offset));
+        }
+        self.heads[fl][sl] = Some(offset);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    fn unlink(&mut self, offset: usize) {
+        let (fl, sl) = Tlsf::mapping(self.size(offset));
+        let (prev, next) = (self.prev_free(offset), self.next_free(offset));
+        match prev {
+            Some(p) => self.set_next_free(p, next),
+            None    => self.heads[fl][sl] = next,
+        }
+        if let Some(n) = next {
+            self.set_prev_free(n, prev);
+        }
+        if self.heads[fl][sl].is_none() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+    }
+
+    /* Block headers and links, stored in the pool */
+
+    fn size(&self, offset: usize) -> usize {
+        self.word(offset) as usize & !FREE
+    }
+
+    fn is_free(&self, offset: usize) -> bool {
+        self.word(offset) as usize & FREE != 0
+    }
+
+    fn set_header(&mut self, offset: usize, size: usize, free: bool) {
+        self.set_word(offset, (size | if free { FREE } else { 0 }) as u64)
+    }
+
+    /* Whether a block starts at `offset`: its header makes sense, and its
+     * neighbours in memory agree */
+    fn is_block(&self, offset: usize) -> bool {
+        let len = self.memory.len();
+        if !offset.is_multiple_of(GRAIN) || offset + MIN_BLOCK > len {
+            return false;
+        }
+        let size = self.size(offset);
+        if size < MIN_BLOCK || !size.is_multiple_of(GRAIN) || offset + size > len {
+            return false;
+        }
+        let prev = match self.prev_phys(offset) {
+            None    => offset == 0,
+            Some(p) => p < offset && self.size(p) == offset - p,
+        };
+        prev && self.next_phys(offset).is_none_or(|n| self.prev_phys(n) == Some(offset))
+    }
+
+    fn next_phys(&self, offset: usize) -> Option<usize> {
+        let next = offset + self.size(offset);
+        if next < self.memory.len() { Some(next) } else { None }
+    }
+
+    fn prev_phys(&self, offset: usize) -> Option<usize> {
+        self.link(offset + 8)
+    }
+
+    fn set_prev_phys(&mut self, offset: usize, prev: Option<usize>) {
+        self.set_link(offset + 8, prev)
+    }
+
+    fn next_free(&self, offset: usize) -> Option<usize> {
+        self.link(offset + HEADER)
+    }
+
+    fn prev_free(&self, offset: usize) -> Option<usize> {
+        self.link(offset + HEADER + 8)
+    }
+
+    fn set_next_free(&mut self, offset: usize, next: Option<usize>) {
+        self.set_link(offset + HEADER, next)
+    }
+
+    fn set_prev_free(&mut self, offset: usize, prev: Option<usize>) {
+        self.set_link(offset + HEADER + 8, prev)
+    }
+
+    fn link(&self, at: usize) -> Option<usize> {
+        match self.word(at) {
+            NIL => None,
+            o   => Some(o as usize),
+        }
+    }
+
+    fn set_link(&mut self, at: usize, link: Option<usize>) {
+        self.set_word(at, link.map_or(NIL, |o| o as u64))
+    }
+
+    fn word(&self, at: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.memory[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn set_word(&mut self, at: usize, word: u64) {
+        self.memory[at..at + 8].copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+impl fmt::Debug for Tlsf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tlsf")
+            .field("capacity", &self.memory.len())
+            .field("live", &self.live)
+            .field("fl_bitmap", &format_args!("{:#034b}", self.fl_bitmap))
+            .finish()
+    }
+}
+
+fn align_up(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_and_merge() {
+        let mut tlsf = Tlsf::new(4096);
+        let blocks: Vec<_> = (1..10).map(|n| tlsf.alloc(n * 24).unwrap()).collect();
+        for (n, &b) in (1..10).zip(&blocks) {
+            assert!(tlsf.bytes(b).len() >= n * 24);
+        }
+        assert_eq!(tlsf.validate(), Ok(()));
+
+        for &b in blocks.iter().step_by(2) {
+            tlsf.free(b);
+        }
+        assert_eq!(tlsf.validate(), Ok(()));
+        for &b in blocks.iter().skip(1).step_by(2) {
+            tlsf.free(b);
+        }
+        assert_eq!(tlsf.validate(), Ok(()));
+        assert_eq!(tlsf.stats().largest_free_run, 4096);
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let mut tlsf = Tlsf::new(1024);
+        assert!(tlsf.alloc(2048).is_none());
+        let b = tlsf.alloc(1024 - HEADER).unwrap();
+        assert!(tlsf.alloc(1).is_none());
+        tlsf.free(b);
+        assert!(tlsf.alloc(1).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let mut tlsf = Tlsf::new(1024);
+        let p = tlsf.alloc(64).unwrap();
+        let _q = tlsf.alloc(64).unwrap();
+        tlsf.free(p);
+        tlsf.free(p);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_after_merge_with_prev() {
+        let mut tlsf = Tlsf::new(1024);
+        let p = tlsf.alloc(64).unwrap();
+        let q = tlsf.alloc(64).unwrap();
+        let _z = tlsf.alloc(64).unwrap();
+        tlsf.free(p);
+        tlsf.free(q);
+        tlsf.free(q);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_after_merge_with_next() {
+        let mut tlsf = Tlsf::new(1024);
+        let p = tlsf.alloc(64).unwrap();
+        let q = tlsf.alloc(64).unwrap();
+        let _z = tlsf.alloc(64).unwrap();
+        tlsf.free(q);
+        tlsf.free(p);
+        tlsf.free(q);
+    }
+
+    #[test]
+    fn validate_finds_uncoalesced() {
+        let mut tlsf = Tlsf::new(1024);
+        let p = tlsf.alloc(64).unwrap();
+        let q = tlsf.alloc(64).unwrap();
+        let _z = tlsf.alloc(64).unwrap();
+        tlsf.free(p);
+        /* Mark q free behind the allocator's back */
+        let size = tlsf.size(q.offset);
+        tlsf.set_header(q.offset, size, true);
+        assert_eq!(tlsf.validate(), Err(Violation::Uncoalesced { list: "tlsf", offset: q.offset }));
+    }
+}
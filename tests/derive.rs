@@ -0,0 +1,133 @@
+//! `#[derive(Trace)]` on every shape of type, checked by collecting.
+
+extern crate allocators;
+
+use allocators::pile::{Pile, Pointer, Trace, WeakPointer};
+
+/* Does not implement `Trace`, so it has to be skipped */
+#[derive(Debug, Default)]
+struct Opaque(u32);
+
+#[derive(Trace)]
+struct Named {
+    value:  u32,
+    next:   Option<Pointer>,
+    kids:   Vec<Pointer>,
+    #[trace(skip)]
+    opaque: Opaque,
+}
+
+#[derive(Trace)]
+struct Tuple(u32, Option<Pointer>, #[trace(skip)] Opaque);
+
+#[derive(Trace)]
+struct Unit;
+
+#[derive(Trace)]
+enum Shape {
+    Leaf,
+    Link(Pointer),
+    Pair { left: Pointer, right: Pointer },
+}
+
+#[derive(Trace)]
+struct Boxed<P> {
+    inner: P,
+}
+
+#[derive(Trace)]
+struct Cache {
+    strong: Option<Pointer>,
+    weak:   Option<WeakPointer>,
+}
+
+/* Everything `t` traces */
+fn traced<T: Trace>(t: &T) -> Vec<Pointer> {
+    let mut pointers = Vec::new();
+    t.trace(&mut |p| pointers.push(p));
+    pointers
+}
+
+#[test]
+fn named_struct() {
+    let mut pile: Pile<Named> = Pile::new();
+    let leaf = || Named { value: 0, next: None, kids: Vec::new(), opaque: Opaque(0) };
+    let next = pile.alloc(leaf()).pointer();
+    let kids: Vec<_> = (0..3).map(|_| pile.alloc(leaf()).pointer()).collect();
+    let lost = pile.alloc(leaf()).pointer();
+    let root = pile.alloc(Named { value: 1, next: Some(next), kids: kids.clone(), opaque: Opaque(7) });
+
+    assert_eq!(traced(&*pile.get(root.pointer()).unwrap()).len(), 4);
+    assert_eq!(pile.collect(), 1);
+    assert!(pile.get(lost).is_none());
+    assert!(pile.get(next).is_some());
+    assert!(kids.iter().all(|&k| pile.get(k).is_some()));
+    assert_eq!(pile.get(root.pointer()).unwrap().opaque.0, 7);
+}
+
+#[test]
+fn tuple_struct() {
+    let mut pile: Pile<Tuple> = Pile::new();
+    let target = pile.alloc(Tuple(0, None, Opaque::default())).pointer();
+    let lost = pile.alloc(Tuple(1, None, Opaque::default())).pointer();
+    let root = pile.alloc(Tuple(2, Some(target), Opaque(7)));
+
+    assert_eq!(pile.collect(), 1);
+    assert_eq!(pile.get(target).unwrap().0, 0);
+    assert_eq!((pile.get(root.pointer()).unwrap().2).0, 7);
+    assert!(pile.get(lost).is_none());
+}
+
+#[test]
+fn unit_struct() {
+    let mut pile: Pile<Unit> = Pile::new();
+    let kept = pile.alloc(Unit);
+    pile.alloc(Unit);
+    assert!(traced(&Unit).is_empty());
+    assert_eq!(pile.collect(), 1);
+    assert!(pile.get(kept.pointer()).is_some());
+}
+
+#[test]
+fn enum_variants() {
+    let mut pile: Pile<Shape> = Pile::new();
+    let a = pile.alloc(Shape::Leaf).pointer();
+    let b = pile.alloc(Shape::Leaf).pointer();
+    let c = pile.alloc(Shape::Link(b)).pointer();
+    let lost = pile.alloc(Shape::Leaf).pointer();
+    let _root = pile.alloc(Shape::Pair { left: a, right: c });
+
+    assert_eq!(pile.collect(), 1);
+    assert!([a, b, c].iter().all(|&p| pile.get(p).is_some()));
+    assert!(pile.get(lost).is_none());
+}
+
+#[test]
+fn generic_struct() {
+    let mut pile: Pile<Boxed<Option<Pointer>>> = Pile::new();
+    let target = pile.alloc(Boxed { inner: None }).pointer();
+    let lost = pile.alloc(Boxed { inner: None }).pointer();
+    let _root = pile.alloc(Boxed { inner: Some(target) });
+
+    assert_eq!(pile.collect(), 1);
+    assert!(pile.get(target).is_some());
+    assert!(pile.get(lost).is_none());
+}
+
+#[test]
+fn weak_fields() {
+    let mut pile: Pile<Cache> = Pile::new();
+    let strong = pile.alloc(Cache { strong: None, weak: None }).pointer();
+    let weakly = pile.alloc(Cache { strong: None, weak: None }).pointer();
+    let weak = pile.downgrade(weakly).unwrap();
+    let root = pile.alloc(Cache { strong: Some(strong), weak: Some(weak) });
+
+    /* Weak pointers are visited by `trace_weak_mut` only */
+    let mut visited = 0;
+    pile.get_mut(root.pointer()).unwrap().trace_weak_mut(&mut |_| visited += 1);
+    assert_eq!(visited, 1);
+
+    assert_eq!(pile.collect(), 1);
+    assert!(pile.get(strong).is_some());
+    assert_eq!(pile.upgrade(weak), None);
+}